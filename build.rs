@@ -22,6 +22,15 @@ fn main() {
         .whitelist_function("bpf_map__def")
         .whitelist_function("bpf_set_link_xdp_fd")
         .whitelist_function("libbpf_num_possible_cpus")
+        .whitelist_function("bpf_object__close")
+        .whitelist_function("bpf_object__open")
+        .whitelist_function("bpf_object__pin_maps")
+        .whitelist_function("bpf_object__unpin_maps")
+        .whitelist_function("bpf_map__next")
+        .whitelist_function("bpf_map__name")
+        .whitelist_function("bpf_map__reuse_fd")
+        .whitelist_function("libbpf_get_error")
+        .whitelist_function("bpf_get_link_xdp_info")
         .blacklist_type(r#"u\d+"#)
         .generate()
         .expect("Unable to generate bindings");
@@ -39,6 +48,12 @@ fn main() {
         .whitelist_function("bpf_map_update_elem")
         .whitelist_function("bpf_map_lookup_elem")
         .whitelist_function("bpf_get_map_by_name")
+        .whitelist_function("bpf_prog_test_run")
+        .whitelist_function("bpf_map_delete_elem")
+        .whitelist_function("bpf_obj_pin")
+        .whitelist_function("bpf_map_get_next_key")
+        .whitelist_function("bpf_obj_get")
+        .whitelist_function("bpf_create_map")
         .blacklist_type(r#"u\d+"#)
         .generate()
         .expect("Unable to generate bindings");
@@ -69,9 +84,31 @@ fn main() {
     let xdp_prog_types_bindings = bindgen::Builder::default()
         .header("./src/xdp_shard.h")
         .derive_default(true)
+        .impl_debug(true)
         .blacklist_type(r#"u\d+"#)
         .whitelist_type(r#"datarec"#)
         .whitelist_type(r#"available_shards"#)
+        .whitelist_type(r#"shard_stats"#)
+        .whitelist_type(r#"port_key"#)
+        .whitelist_type(r#"port_rec"#)
+        .whitelist_type(r#"override_key"#)
+        .whitelist_type(r#"override_rec"#)
+        .whitelist_type(r#"shard_sample"#)
+        .whitelist_var(r#"MAX_FIELD_SIZE"#)
+        .whitelist_var(r#"MAX_KEY_FIELDS"#)
+        .whitelist_var(r#"SHARD_MODE_.*"#)
+        .whitelist_var(r#"HASH_.*"#)
+        .whitelist_var(r#"MAGLEV_TABLE_SIZE"#)
+        .whitelist_var(r#"IP_COUNT_.*"#)
+        .whitelist_var(r#"MAX_CPUS"#)
+        .whitelist_var(r#"MAX_RXQs"#)
+        .whitelist_var(r#"MAX_IFACES"#)
+        .whitelist_var(r#"MAX_SHARDS"#)
+        .whitelist_var(r#"SKETCH_.*"#)
+        .whitelist_var(r#"SAMPLE_.*"#)
+        .whitelist_var(r#"DELIVER_.*"#)
+        .whitelist_var(r#"DROP_.*"#)
+        .whitelist_var(r#"UNSHARDED_.*"#)
         .generate()
         .expect("Unable to generate bindings");
     xdp_prog_types_bindings
@@ -88,12 +125,38 @@ fn main() {
 
     println!("cargo:rerun-if-changed=./src/xdp_shard.c");
     println!("cargo:rerun-if-changed=./src/xdp_shard.h");
+    println!("cargo:rerun-if-changed=./src/hash.h");
+    println!("cargo:rerun-if-changed=./src/tc_unshard.c");
+    compile_bpf("xdp_shard", "xdp_shard", &[], &clang_include, &out_path);
+    // kernels before 5.8 have no ring buffers: this one samples through perf buffers.
+    compile_bpf(
+        "xdp_shard",
+        "xdp_shard_perf",
+        &["-DSAMPLE_PERF"],
+        &clang_include,
+        &out_path,
+    );
+    compile_bpf("tc_unshard", "tc_unshard", &[], &clang_include, &out_path);
+}
+
+/// Compile ./src/<name>.c with `defines` into <out_path>/<obj_name>.o
+fn compile_bpf(
+    name: &str,
+    obj_name: &str,
+    defines: &[&str],
+    clang_include: &str,
+    out_path: &std::path::Path,
+) {
+    let c_file = format!("{}.c", name);
+    let ll_file = format!("{}.d", obj_name);
+    let obj_file = format!("{}.o", obj_name);
+
     if !std::process::Command::new("clang")
         .current_dir("./src")
         .args(&[
             "-nostdinc",
             "-isystem",
-            &clang_include,
+            clang_include,
             "-D__KERNEL__",
             "-D__ASM_SYSREG_H",
             "-I/usr/include",
@@ -102,51 +165,43 @@ fn main() {
             "-gdwarf",
             "-O1",
             "-emit-llvm",
-            "-c",
-            "xdp_shard.c",
-            "-o",
-            "xdp_shard.d",
         ])
+        .args(defines)
+        .args(&["-c", &c_file, "-o", &ll_file])
         .spawn()
-        .expect("clang xdp_shard.c")
+        .expect("clang")
         .wait()
-        .expect("clang xdp_shard.c")
+        .expect("clang")
         .success()
     {
-        panic!("clang errored");
+        panic!("clang errored: {}", c_file);
     }
 
     if !std::process::Command::new("llc")
         .current_dir("./src")
-        .args(&[
-            "-march=bpf",
-            "-filetype=obj",
-            "-o",
-            "xdp_shard.o",
-            "xdp_shard.d",
-        ])
+        .args(&["-march=bpf", "-filetype=obj", "-o", &obj_file, &ll_file])
         .spawn()
-        .expect("llc xdp_shard.d")
+        .expect("llc")
         .wait()
-        .expect("llc xdp_shard.d")
+        .expect("llc")
         .success()
     {
-        panic!("llc errored");
+        panic!("llc errored: {}", ll_file);
     }
 
     std::process::Command::new("rm")
         .current_dir("./src")
-        .args(&["-f", "xdp_shard.d"])
+        .args(&["-f", &ll_file])
         .spawn()
         .unwrap()
         .wait()
         .unwrap();
 
-    eprintln!("{:?}", out_path.join("xdp_shard.o"));
+    eprintln!("{:?}", out_path.join(&obj_file));
 
     if !std::process::Command::new("mv")
-        .arg("src/xdp_shard.o")
-        .arg(out_path.join("xdp_shard.o"))
+        .arg(format!("src/{}", obj_file))
+        .arg(out_path.join(&obj_file))
         .spawn()
         .expect("kernel ebpf program move to outdir")
         .wait()
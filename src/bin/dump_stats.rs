@@ -8,8 +8,32 @@ type StdError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
-    #[structopt(short = "i", long = "interface")]
-    interface: String,
+    /// Interface to load onto. Repeat to load one program onto several interfaces.
+    #[structopt(short = "i", long = "interface", required = true)]
+    interface: Vec<String>,
+
+    /// XDP attach mode: driver or skb.
+    #[structopt(long = "xdp-mode", default_value = "skb")]
+    xdp_mode: xdp_shard::XdpMode,
+
+    /// If attaching in driver mode fails, attach in skb mode.
+    #[structopt(long = "xdp-fallback")]
+    xdp_fallback: bool,
+
+    /// Read the stats of the instance pinned on the (first) interface, instead of loading a new
+    /// one.
+    #[structopt(long = "pinned")]
+    pinned: bool,
+
+    /// Print this many of the most frequent shard keys of each sharded port, with hot key
+    /// tracking turned on for them while this runs. Ports are only sharded in a --pinned
+    /// instance.
+    #[structopt(long = "hot-keys")]
+    hot_keys: Option<usize>,
+
+    /// Print 1 in this many sharding decisions. Only --pinned instances shard.
+    #[structopt(long = "sample-every")]
+    sample_every: Option<u32>,
 }
 
 fn main() -> Result<(), StdError> {
@@ -17,8 +41,16 @@ fn main() -> Result<(), StdError> {
 
     tracing_subscriber::fmt::init();
 
-    let mut prog = xdp_shard::BpfHandles::load_on_interface_name(&opt.interface)?;
-    let ifn = opt.interface;
+    let mut prog = if opt.pinned {
+        xdp_shard::BpfHandles::open_pinned(&opt.interface[0])?
+    } else {
+        let names: Vec<&str> = opt.interface.iter().map(String::as_str).collect();
+        xdp_shard::BpfHandles::load_on_interface_names_with_mode(
+            &names,
+            opt.xdp_mode,
+            opt.xdp_fallback,
+        )?
+    };
     let stop: Arc<AtomicBool> = Arc::new(false.into());
     let s = stop.clone();
     ctrlc::set_handler(move || {
@@ -27,22 +59,118 @@ fn main() -> Result<(), StdError> {
     })
     .unwrap();
 
+    // the ports we turn hot key tracking on for, to turn it off again when we stop.
+    let mut tracked = vec![];
+    if opt.hot_keys.is_some() {
+        for port in prog.sharded_ports() {
+            if !prog.tracks_hot_keys(port) {
+                prog.track_hot_keys(port, true)?;
+                tracked.push(port);
+            }
+        }
+    }
+
+    let mut samples = match opt.sample_every {
+        Some(every) => {
+            prog.sample_decisions(every)?;
+            Some(xdp_shard::sample::SampleReader::new(&prog)?)
+        }
+        None => None,
+    };
+
+    let mut prev_drops = prog.get_drop_stats()?;
     while !stop.load(std::sync::atomic::Ordering::SeqCst) {
-        std::time::Duration::from_secs(1);
-        let (stats, prev) = prog.get_stats()?;
-
-        let mut rxqs = stats.get_rxq_cpu_port_count();
-        let prev_rxqs = prev.get_rxq_cpu_port_count();
-        xdp_shard::diff_maps(&mut rxqs, &prev_rxqs);
-        for (rxq, cpus) in rxqs.iter().enumerate() {
-            for (cpu, portcounts) in cpus.iter().enumerate() {
-                for (port, count) in portcounts.iter() {
-                    if *count > 0 {
-                        tracing::info!(interface = ?&ifn, rxq, cpu, port, count, "");
+        for (ifindex, stats, prev) in prog.get_interface_stats()? {
+            let mut rxqs = stats.get_rxq_cpu_port_count();
+            let prev_rxqs = prev.get_rxq_cpu_port_count();
+            xdp_shard::diff_maps(&mut rxqs, &prev_rxqs);
+            let mut rxq_bytes = stats.get_rxq_cpu_port_bytes();
+            let prev_rxq_bytes = prev.get_rxq_cpu_port_bytes();
+            xdp_shard::diff_maps(&mut rxq_bytes, &prev_rxq_bytes);
+            for (rxq, (cpus, cpu_bytes)) in rxqs.iter().zip(rxq_bytes.iter()).enumerate() {
+                for (cpu, (portcounts, portbytes)) in cpus.iter().zip(cpu_bytes.iter()).enumerate()
+                {
+                    for (port, count) in portcounts.iter() {
+                        if *count > 0 {
+                            let bytes = portbytes.get(port).copied().unwrap_or(0);
+                            tracing::info!(ifindex, rxq, cpu, port, count, bytes, "");
+                        }
+                    }
+                }
+            }
+
+            let ips = stats.get_rxq_cpu_ip_counts();
+            let prev_ips = prev.get_rxq_cpu_ip_counts();
+            for (rxq, (cpus, prev_cpus)) in ips.iter().zip(prev_ips.iter()).enumerate() {
+                for (cpu, (c, p)) in cpus.iter().zip(prev_cpus.iter()).enumerate() {
+                    let options = c.options.saturating_sub(p.options);
+                    let bad_hdr = c.bad_hdr.saturating_sub(p.bad_hdr);
+                    let fragments = c.fragments.saturating_sub(p.fragments);
+                    if options > 0 || bad_hdr > 0 || fragments > 0 {
+                        tracing::info!(
+                            ifindex,
+                            rxq,
+                            cpu,
+                            options,
+                            bad_hdr,
+                            fragments,
+                            "ip headers"
+                        );
                     }
                 }
             }
         }
+
+        let drops = prog.get_drop_stats()?;
+        for (cpu, (reasons, prev_reasons)) in drops.iter().zip(prev_drops.iter()).enumerate() {
+            for (reason, count) in reasons.iter() {
+                let count = count.saturating_sub(*prev_reasons.get(reason).unwrap_or(&0));
+                if count > 0 {
+                    tracing::info!(cpu, ?reason, count, "drops");
+                }
+            }
+        }
+
+        prev_drops = drops;
+
+        if let Some(k) = opt.hot_keys {
+            for port in prog.sharded_ports() {
+                for hot in prog.hot_keys(port, k)? {
+                    let key: String = hot.key.iter().map(|b| format!("{:02x}", b)).collect();
+                    tracing::info!(port, ?key, count = hot.count, "hot key");
+                }
+            }
+        }
+
+        if let Some(samples) = samples.as_mut() {
+            samples.poll(|s| {
+                let key: String = s.key.iter().map(|b| format!("{:02x}", b)).collect();
+                tracing::info!(
+                    src_addr = %s.src_addr,
+                    orig_port = s.orig_port,
+                    ?key,
+                    hash = s.hash,
+                    shard = ?s.shard,
+                    port = s.port,
+                    tcp = s.tcp,
+                    ifindex = s.ifindex,
+                    rxq = s.rxq,
+                    cpu = s.cpu,
+                    "sample"
+                );
+            });
+        }
+
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+
+    // a pinned instance outlives us.
+    if samples.is_some() {
+        prog.sample_decisions(0)?;
+    }
+
+    for port in tracked {
+        prog.track_hot_keys(port, false)?;
     }
 
     Ok(())
@@ -11,8 +11,24 @@ struct Opt {
     #[structopt(short = "i", long = "interface")]
     interface: String,
 
+    /// XDP attach mode: driver or skb.
+    #[structopt(long = "xdp-mode", default_value = "skb")]
+    xdp_mode: xdp_shard::XdpMode,
+
+    /// If attaching in driver mode fails, attach in skb mode.
+    #[structopt(long = "xdp-fallback")]
+    xdp_fallback: bool,
+
     #[structopt(short = "p", long = "port")]
     ports: Vec<u16>,
+
+    /// Rewrite replies from the shard ports to come from the original port.
+    #[structopt(long = "unshard-egress")]
+    unshard_egress: bool,
+
+    /// Pin the program and maps, so that sharding keeps going after we exit.
+    #[structopt(long = "pin", conflicts_with = "unshard_egress")]
+    pin: bool,
 }
 
 fn dump_ctrs(
@@ -95,7 +111,19 @@ async fn main() -> Result<(), StdError> {
         start_sharding_tx.send(()).unwrap();
     });
 
-    let mut prog = xdp_shard::BpfHandles::load_on_interface_name(&opt.interface)?;
+    let mut prog = xdp_shard::BpfHandles::load_on_interface_name_with_mode(
+        &opt.interface,
+        opt.xdp_mode,
+        opt.xdp_fallback,
+    )?;
+    if opt.unshard_egress {
+        prog.unshard_egress()?;
+    }
+
+    if opt.pin {
+        prog.pin()?;
+    }
+
     let ifn = opt.interface;
 
     let stop: Arc<AtomicBool> = Arc::new(false.into());
@@ -26,6 +26,16 @@ pub mod if_link {
     include!(concat!(env!("OUT_DIR"), "/if_link.rs"));
 }
 
+#[allow(non_upper_case_globals)]
+#[allow(non_camel_case_types)]
+#[allow(non_snake_case)]
+#[allow(unused)]
+pub mod if_xdp {
+    // if_xdp.h bindings, for AF_XDP sockets
+    include!(concat!(env!("OUT_DIR"), "/if_xdp.rs"));
+}
+
+#[allow(non_upper_case_globals)]
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
 pub mod xdp_shard {
@@ -33,5 +43,12 @@ pub mod xdp_shard {
 
     pub type AvailableShards = available_shards;
     pub type ShardRules = shard_rules;
+    pub type ShardField = shard_field;
     pub type Datarec = datarec;
+    pub type PortKey = port_key;
+    pub type PortRec = port_rec;
+    pub type ShardStatsRec = shard_stats;
+    pub type OverrideKey = override_key;
+    pub type OverrideRec = override_rec;
+    pub type ShardSampleRec = shard_sample;
 }
@@ -0,0 +1,248 @@
+/* Shard key hashes, fed one key field at a time. src/hash.rs has a matching implementation of
+ * each.
+ *
+ * hash_init(), then hash_update() for each key field, then hash_final().
+ */
+
+#define FNV1A_64_INIT ((__u64)0xcbf29ce484222325ULL)
+#define FNV_64_PRIME ((__u64)0x100000001b3ULL)
+
+#define CRC32_POLY 0xedb88320 // IEEE, bit-reflected
+
+#define XXH_PRIME32_1 0x9e3779b1U
+#define XXH_PRIME32_2 0x85ebca77U
+#define XXH_PRIME32_3 0xc2b2ae3dU
+#define XXH_PRIME32_4 0x27d4eb2fU
+#define XXH_PRIME32_5 0x165667b1U
+
+#define ROTL64(x, b) (((x) << (b)) | ((x) >> (64 - (b))))
+#define ROTL32(x, b) ((__u32) (((x) << (b)) | ((x) >> (32 - (b)))))
+
+struct hash_state {
+    __u8 fn; // HASH_*
+    __u32 len; // total key length. xxhash32 needs it up front.
+    __u32 n; // bytes so far
+    __u64 h; // FNV-1a, CRC32 and identity state, and the xxhash32 accumulator
+    __u64 m; // the word being filled, for SipHash and xxhash32
+    __u64 v[4]; // SipHash v0..v3, or the xxhash32 lanes
+};
+
+static inline void sipround(struct hash_state *st)
+{
+    st->v[0] += st->v[1]; st->v[1] = ROTL64(st->v[1], 13); st->v[1] ^= st->v[0]; st->v[0] = ROTL64(st->v[0], 32);
+    st->v[2] += st->v[3]; st->v[3] = ROTL64(st->v[3], 16); st->v[3] ^= st->v[2];
+    st->v[0] += st->v[3]; st->v[3] = ROTL64(st->v[3], 21); st->v[3] ^= st->v[0];
+    st->v[2] += st->v[1]; st->v[1] = ROTL64(st->v[1], 17); st->v[1] ^= st->v[2]; st->v[2] = ROTL64(st->v[2], 32);
+}
+
+static inline __u32 xxh32_round(__u32 lane, __u32 word)
+{
+    lane += word * XXH_PRIME32_2;
+    lane = ROTL32(lane, 13);
+    return lane * XXH_PRIME32_1;
+}
+
+static inline void hash_init(struct hash_state *st, __u8 fn, __u64 k0, __u64 k1, __u32 len)
+{
+    __u32 seed = (__u32) k0;
+
+    __builtin_memset(st, 0, sizeof(*st));
+    st->fn = fn;
+    st->len = len;
+
+    switch (fn) {
+    case HASH_SIPHASH13:
+        st->v[0] = k0 ^ 0x736f6d6570736575ULL;
+        st->v[1] = k1 ^ 0x646f72616e646f6dULL;
+        st->v[2] = k0 ^ 0x6c7967656e657261ULL;
+        st->v[3] = k1 ^ 0x7465646279746573ULL;
+        break;
+    case HASH_CRC32:
+        st->h = 0xffffffff;
+        break;
+    case HASH_XXHASH32:
+        if (len >= 16) {
+            st->v[0] = (__u32) (seed + XXH_PRIME32_1 + XXH_PRIME32_2);
+            st->v[1] = (__u32) (seed + XXH_PRIME32_2);
+            st->v[2] = seed;
+            st->v[3] = (__u32) (seed - XXH_PRIME32_1);
+        } else {
+            st->h = (__u32) (seed + XXH_PRIME32_5 + len);
+        }
+        break;
+    case HASH_IDENTITY:
+    case HASH_IDENTITY_LE:
+        break;
+    default:
+        st->h = FNV1A_64_INIT;
+        break;
+    }
+}
+
+static inline void xxh32_update(struct hash_state *st, __u8 b)
+{
+    __u32 stripes_end = st->len & ~15U;
+    __u32 words_end = st->len & ~3U;
+    __u32 word;
+    __u32 h;
+
+    if (st->n >= words_end) {
+        // trailing bytes
+        h = (__u32) st->h + b * XXH_PRIME32_5;
+        st->h = ROTL32(h, 11) * XXH_PRIME32_1;
+        return;
+    }
+
+    // words are little-endian
+    st->m |= ((__u64) b) << ((st->n & 3) * 8);
+    if ((st->n & 3) != 3) {
+        return;
+    }
+
+    word = (__u32) st->m;
+    st->m = 0;
+    if (st->n >= stripes_end) {
+        // trailing words
+        h = (__u32) st->h + word * XXH_PRIME32_3;
+        st->h = ROTL32(h, 17) * XXH_PRIME32_4;
+        return;
+    }
+
+    // 16-byte stripes, one word per lane.
+    switch ((st->n >> 2) & 3) {
+    case 0:
+        st->v[0] = xxh32_round(st->v[0], word);
+        break;
+    case 1:
+        st->v[1] = xxh32_round(st->v[1], word);
+        break;
+    case 2:
+        st->v[2] = xxh32_round(st->v[2], word);
+        break;
+    default:
+        st->v[3] = xxh32_round(st->v[3], word);
+        break;
+    }
+
+    if (st->n + 1 == stripes_end) {
+        // that was the last stripe: merge the lanes.
+        h = ROTL32((__u32) st->v[0], 1) + ROTL32((__u32) st->v[1], 7)
+            + ROTL32((__u32) st->v[2], 12) + ROTL32((__u32) st->v[3], 18);
+        st->h = (__u32) (h + st->len);
+    }
+}
+
+static inline void siphash13_update(struct hash_state *st, __u8 b)
+{
+    // words are little-endian
+    st->m |= ((__u64) b) << ((st->n & 7) * 8);
+    if ((st->n & 7) == 7) {
+        st->v[3] ^= st->m;
+        sipround(st);
+        st->v[0] ^= st->m;
+        st->m = 0;
+    }
+}
+
+static inline void crc32_update(struct hash_state *st, __u8 b)
+{
+    __u32 crc = ((__u32) st->h) ^ b;
+    __u8 j;
+
+    #pragma clang loop unroll(full)
+    for (j = 0; j < 8; j++) {
+        crc = (crc >> 1) ^ (CRC32_POLY & -(crc & 1));
+    }
+    st->h = crc;
+}
+
+static inline void identity_update(struct hash_state *st, __u8 b)
+{
+    // big-endian: the last 8 bytes stay.
+    st->h = (st->h << 8) | b;
+}
+
+static inline void identity_le_update(struct hash_state *st, __u8 b)
+{
+    // little-endian: the first 8 bytes count.
+    if (st->n < 8) {
+        st->h |= ((__u64) b) << (st->n * 8);
+    }
+}
+
+static inline void fnv1a_update(struct hash_state *st, __u8 b)
+{
+    st->h ^= (__u64) b;
+    st->h *= FNV_64_PRIME;
+}
+
+// feed buf[0..len) to update, unrolled to MAX_FIELD_SIZE for the verifier.
+#define HASH_BYTES(update)                      \
+    _Pragma("clang loop unroll(full)")          \
+    for (i = 0; i < MAX_FIELD_SIZE; i++) {      \
+        if (i >= len) {                         \
+            break;                              \
+        }                                       \
+        update(st, buf[i]);                     \
+        st->n++;                                \
+    }
+
+/* Hash the len bytes at buf, len <= MAX_FIELD_SIZE. The hash function is picked once per call,
+ * not per byte, so that each unrolled loop is straight-line code.
+ */
+static inline void hash_update(struct hash_state *st, const __u8 *buf, __u8 len)
+{
+    __u8 i;
+
+    switch (st->fn) {
+    case HASH_SIPHASH13:
+        HASH_BYTES(siphash13_update);
+        break;
+    case HASH_CRC32:
+        HASH_BYTES(crc32_update);
+        break;
+    case HASH_XXHASH32:
+        HASH_BYTES(xxh32_update);
+        break;
+    case HASH_IDENTITY:
+        HASH_BYTES(identity_update);
+        break;
+    case HASH_IDENTITY_LE:
+        HASH_BYTES(identity_le_update);
+        break;
+    default:
+        HASH_BYTES(fnv1a_update);
+        break;
+    }
+}
+
+static inline __u64 hash_final(struct hash_state *st)
+{
+    __u64 b;
+    __u32 h;
+
+    switch (st->fn) {
+    case HASH_SIPHASH13:
+        b = (((__u64) st->len) << 56) | st->m;
+        st->v[3] ^= b;
+        sipround(st);
+        st->v[0] ^= b;
+        st->v[2] ^= 0xff;
+        sipround(st);
+        sipround(st);
+        sipround(st);
+        return st->v[0] ^ st->v[1] ^ st->v[2] ^ st->v[3];
+    case HASH_CRC32:
+        return ((__u32) st->h) ^ 0xffffffff;
+    case HASH_XXHASH32:
+        h = (__u32) st->h;
+        h ^= h >> 15;
+        h *= XXH_PRIME32_2;
+        h ^= h >> 13;
+        h *= XXH_PRIME32_3;
+        h ^= h >> 16;
+        return h;
+    default:
+        return st->h;
+    }
+}
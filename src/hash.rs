@@ -0,0 +1,173 @@
+//! The shard key hashes of the XDP program (`hash.h`), so that clients can predict which shard a
+//! key goes to.
+//!
+//! The key is the rule's fields concatenated in order (see [`shard_key`]). With
+//! [`ShardMode::Modulo`](crate::ShardMode::Modulo), the shard index is then `hash % num_shards`,
+//! and for range rules it is [`range_index`] of the key as an integer.
+
+const FNV1A_64_INIT: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_64_PRIME: u64 = 0x100_0000_01b3;
+
+const CRC32_POLY: u32 = 0xedb8_8320;
+
+const XXH_PRIME32_1: u32 = 0x9e37_79b1;
+const XXH_PRIME32_2: u32 = 0x85eb_ca77;
+const XXH_PRIME32_3: u32 = 0xc2b2_ae3d;
+const XXH_PRIME32_4: u32 = 0x27d4_eb2f;
+const XXH_PRIME32_5: u32 = 0x1656_67b1;
+
+/// The shard key of `payload` under a rule with `fields`, a list of `(msg_offset, field_size)`.
+///
+/// Returns `None` if the payload is too short, in which case the XDP program does not shard it.
+pub fn shard_key(payload: &[u8], fields: &[(u8, u8)]) -> Option<Vec<u8>> {
+    let mut key = Vec::new();
+    for &(msg_offset, field_size) in fields {
+        let start = msg_offset as usize;
+        key.extend_from_slice(payload.get(start..start + field_size as usize)?);
+    }
+
+    Some(key)
+}
+
+/// 64-bit FNV-1a.
+pub fn fnv1a_64(key: &[u8]) -> u64 {
+    key.iter().fold(FNV1A_64_INIT, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(FNV_64_PRIME)
+    })
+}
+
+fn sipround(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+/// SipHash-1-3 with the 128-bit key `k` (`k[0]` is the first 8 bytes, little-endian).
+pub fn siphash13(k: [u64; 2], key: &[u8]) -> u64 {
+    let mut v = [
+        k[0] ^ 0x736f_6d65_7073_6575,
+        k[1] ^ 0x646f_7261_6e64_6f6d,
+        k[0] ^ 0x6c79_6765_6e65_7261,
+        k[1] ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut words = key.chunks_exact(8);
+    for w in &mut words {
+        let m = u64::from_le_bytes([w[0], w[1], w[2], w[3], w[4], w[5], w[6], w[7]]);
+        v[3] ^= m;
+        sipround(&mut v);
+        v[0] ^= m;
+    }
+
+    let mut b = (key.len() as u64) << 56;
+    for (i, &byte) in words.remainder().iter().enumerate() {
+        b |= (byte as u64) << (8 * i);
+    }
+
+    v[3] ^= b;
+    sipround(&mut v);
+    v[0] ^= b;
+    v[2] ^= 0xff;
+    for _ in 0..3 {
+        sipround(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// CRC-32 (IEEE 802.3, as in zlib).
+pub fn crc32(key: &[u8]) -> u32 {
+    !key.iter().fold(!0u32, |mut crc, &b| {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (CRC32_POLY & (crc & 1).wrapping_neg());
+        }
+
+        crc
+    })
+}
+
+fn xxh32_round(lane: u32, word: u32) -> u32 {
+    lane.wrapping_add(word.wrapping_mul(XXH_PRIME32_2))
+        .rotate_left(13)
+        .wrapping_mul(XXH_PRIME32_1)
+}
+
+fn le_u32(w: &[u8]) -> u32 {
+    u32::from_le_bytes([w[0], w[1], w[2], w[3]])
+}
+
+/// xxHash32 with `seed`.
+pub fn xxhash32(seed: u32, key: &[u8]) -> u32 {
+    let mut stripes = key.chunks_exact(16);
+    let mut h = if key.len() >= 16 {
+        let mut lanes = [
+            seed.wrapping_add(XXH_PRIME32_1).wrapping_add(XXH_PRIME32_2),
+            seed.wrapping_add(XXH_PRIME32_2),
+            seed,
+            seed.wrapping_sub(XXH_PRIME32_1),
+        ];
+        for s in &mut stripes {
+            for (lane, w) in lanes.iter_mut().zip(s.chunks_exact(4)) {
+                *lane = xxh32_round(*lane, le_u32(w));
+            }
+        }
+
+        lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(XXH_PRIME32_5)
+    };
+
+    h = h.wrapping_add(key.len() as u32);
+
+    let mut words = stripes.remainder().chunks_exact(4);
+    for w in &mut words {
+        h = h
+            .wrapping_add(le_u32(w).wrapping_mul(XXH_PRIME32_3))
+            .rotate_left(17)
+            .wrapping_mul(XXH_PRIME32_4);
+    }
+
+    for &b in words.remainder() {
+        h = h
+            .wrapping_add((b as u32).wrapping_mul(XXH_PRIME32_5))
+            .rotate_left(11)
+            .wrapping_mul(XXH_PRIME32_1);
+    }
+
+    h ^= h >> 15;
+    h = h.wrapping_mul(XXH_PRIME32_2);
+    h ^= h >> 13;
+    h = h.wrapping_mul(XXH_PRIME32_3);
+    h ^ (h >> 16)
+}
+
+/// The key as a big-endian integer. Keys longer than 8 bytes keep their last 8 bytes.
+pub fn identity(key: &[u8]) -> u64 {
+    key.iter().fold(0, |hash, &b| (hash << 8) | b as u64)
+}
+
+/// The key as a little-endian integer. Keys longer than 8 bytes use their first 8 bytes.
+pub fn identity_le(key: &[u8]) -> u64 {
+    key.iter()
+        .take(8)
+        .enumerate()
+        .fold(0, |hash, (i, &b)| hash | (b as u64) << (8 * i))
+}
+
+/// The shard index of integer key `key` under a range rule with sorted split points `splits`:
+/// the number of split points `<= key`.
+pub fn range_index(splits: &[u64], key: u64) -> usize {
+    splits.iter().take_while(|&&s| s <= key).count()
+}
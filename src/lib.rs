@@ -4,29 +4,144 @@ type StdError = Box<dyn std::error::Error + Send + Sync + 'static>;
 pub mod bindings;
 use bindings::*;
 
+pub mod hash;
+pub mod maglev;
+pub mod sample;
+pub mod xsk;
+
 pub fn diff_maps(curr: &mut Vec<Vec<HashMap<u16, usize>>>, prev: &Vec<Vec<HashMap<u16, usize>>>) {
     for (curr, prev) in curr.iter_mut().zip(prev.iter()) {
         for (curr, prev) in curr.iter_mut().zip(prev.iter()) {
             for (port, count) in curr.iter_mut() {
                 if prev.contains_key(port) {
-                    *count -= prev.get(port).unwrap();
+                    // counts start over if the port's stats entry was evicted in between.
+                    *count = count.saturating_sub(*prev.get(port).unwrap());
                 }
             }
         }
     }
 }
 
-use xdp_shard::{AvailableShards, Datarec, ShardRules};
+use xdp_shard::{
+    AvailableShards, Datarec, OverrideKey, OverrideRec, PortKey, PortRec, ShardField, ShardRules,
+    ShardStatsRec,
+};
+
+/// Packet and byte counts for one port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortCounts {
+    pub packets: usize,
+    /// Total packet (frame) length.
+    pub bytes: usize,
+}
+
+/// Per-rxq counts of IP packets that were not sharded because of their IP header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IpCounts {
+    /// IPv4 packets with IP options. These are still parsed and sharded.
+    pub options: usize,
+    /// IPv4 packets with an invalid header length. These are dropped.
+    pub bad_hdr: usize,
+    /// Non-first IPv4 or IPv6 fragments, which carry no L4 header. These are passed unchanged.
+    pub fragments: usize,
+}
+
+/// Per-cpu counts of what happened to packets for one sharded port.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShardStats {
+    /// Packets rewritten to each shard, indexed like the rule's ports.
+    pub shards: Vec<usize>,
+    /// Packets not rewritten because the rule has no shards.
+    pub disabled: usize,
+    /// Packets not rewritten because the payload is too short for the shard key.
+    pub short_msg: usize,
+    /// Packets not rewritten because the rule is invalid, or lacks its maglev table or range
+    /// split points.
+    pub bad_rule: usize,
+    /// TCP segments not rewritten because their connection's SYN was not sharded.
+    pub tcp_midflow: usize,
+}
+
+/// A shard key pinned to a port with [`BpfHandles::pin_key`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PinnedKey {
+    pub key: Vec<u8>,
+    pub port: u16,
+    /// Packets rewritten to `port` since the key was pinned.
+    pub hits: usize,
+}
+
+/// A frequent shard key, from [`BpfHandles::hot_keys`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HotKey {
+    pub key: Vec<u8>,
+    /// Estimated packets with this key since the rule's shards were last set. This can
+    /// overestimate, but not underestimate.
+    pub count: usize,
+}
+
+/// Why the XDP program aborted or dropped a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// Too short for a header it claims to have.
+    ShortPacket,
+    /// Invalid shard rule.
+    BadRules,
+    /// Maglev rule without a lookup table.
+    NoMaglevTable,
+    /// Shard index out of range.
+    BadShardIndex,
+    /// Arrived on an interface the program was not loaded on.
+    BadIfindex,
+    /// No stats record for the rx queue.
+    NoRxqRecord,
+    /// IPv4 header length invalid. These are dropped rather than aborted.
+    BadIpHeader,
+    /// TCP data offset invalid.
+    BadTcpHeader,
+    /// Range rule without split points.
+    NoRangeSplits,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 9] = [
+        DropReason::ShortPacket,
+        DropReason::BadRules,
+        DropReason::NoMaglevTable,
+        DropReason::BadShardIndex,
+        DropReason::BadIfindex,
+        DropReason::NoRxqRecord,
+        DropReason::BadIpHeader,
+        DropReason::BadTcpHeader,
+        DropReason::NoRangeSplits,
+    ];
+
+    fn map_key(self) -> u32 {
+        match self {
+            DropReason::ShortPacket => xdp_shard::DROP_SHORT_PACKET,
+            DropReason::BadRules => xdp_shard::DROP_BAD_RULES,
+            DropReason::NoMaglevTable => xdp_shard::DROP_NO_MAGLEV_TABLE,
+            DropReason::BadShardIndex => xdp_shard::DROP_BAD_SHARD_IDX,
+            DropReason::BadIfindex => xdp_shard::DROP_BAD_IFINDEX,
+            DropReason::NoRxqRecord => xdp_shard::DROP_NO_RXQ_RECORD,
+            DropReason::BadIpHeader => xdp_shard::DROP_BAD_IP_HDR,
+            DropReason::BadTcpHeader => xdp_shard::DROP_BAD_TCP_HDR,
+            DropReason::NoRangeSplits => xdp_shard::DROP_NO_RANGE_SPLITS,
+        }
+    }
+}
 
 #[repr(C)]
 #[derive(Debug)]
 pub struct Record {
     timestamp: std::time::Instant,
     cpu: Vec<Datarec>,
+    // (proto, port) -> per-cpu counts. Packets without a port (ICMP) use port 0.
+    ports: HashMap<(u8, u16), Vec<PortRec>>,
 }
 
 impl Record {
-    // map_key is the rxq
+    // map_key is the rxq slot
     fn update(&mut self, map_key: usize, map: *mut libbpf::bpf_map) -> Result<(), StdError> {
         // collect stats.
         let fd = unsafe { libbpf::bpf_map__fd(map) };
@@ -52,53 +167,128 @@ impl Record {
         Ok(())
     }
 
-    pub fn get_cpu_port_count(&self) -> Vec<HashMap<u16, usize>> {
-        self.cpu
-            .iter()
-            .map(|d| {
-                let mut h: HashMap<u16, usize> = d.ports[..]
-                    .iter()
-                    .enumerate()
-                    .take_while(|(_, x)| **x != 0)
-                    .map(|(idx, port)| (*port, d.counts[idx] as usize))
-                    .collect();
-                if d.counts[16] > 0 {
-                    h.insert(0, d.counts[16] as usize);
+    // Vec<HashMap<(u8, u16), PortCounts>> means: cpu_id -> (proto, port) -> counts
+    pub fn get_cpu_proto_port_counts(&self) -> Vec<HashMap<(u8, u16), PortCounts>> {
+        let mut cpus = vec![HashMap::new(); self.cpu.len()];
+        for (key, percpu) in self.ports.iter() {
+            for (cpu, rec) in cpus.iter_mut().zip(percpu.iter()) {
+                if rec.count > 0 {
+                    cpu.insert(
+                        *key,
+                        PortCounts {
+                            packets: rec.count as _,
+                            bytes: rec.bytes as _,
+                        },
+                    );
+                }
+            }
+        }
+
+        cpus
+    }
+
+    // sums val over protocols.
+    fn get_cpu_port_vals(&self, val: impl Fn(&PortCounts) -> usize) -> Vec<HashMap<u16, usize>> {
+        self.get_cpu_proto_port_counts()
+            .into_iter()
+            .map(|ports| {
+                let mut h: HashMap<u16, usize> = HashMap::new();
+                for ((_, port), counts) in ports.iter() {
+                    *h.entry(*port).or_insert(0) += val(counts);
                 }
 
                 h
             })
             .collect()
     }
+
+    pub fn get_cpu_port_count(&self) -> Vec<HashMap<u16, usize>> {
+        self.get_cpu_port_vals(|c| c.packets)
+    }
+
+    /// Like [`get_cpu_port_count`], but the total length in bytes of the packets.
+    pub fn get_cpu_port_bytes(&self) -> Vec<HashMap<u16, usize>> {
+        self.get_cpu_port_vals(|c| c.bytes)
+    }
+
+    pub fn get_cpu_ip_counts(&self) -> Vec<IpCounts> {
+        self.cpu
+            .iter()
+            .map(|d| IpCounts {
+                options: d.ip_counts[xdp_shard::IP_COUNT_OPTIONS as usize] as _,
+                bad_hdr: d.ip_counts[xdp_shard::IP_COUNT_BAD_HDR as usize] as _,
+                fragments: d.ip_counts[xdp_shard::IP_COUNT_FRAGMENT as usize] as _,
+            })
+            .collect()
+    }
 }
 
+/// Stats of one interface.
 #[repr(C)]
 #[derive(Debug)]
 pub struct StatsRecord {
+    // the interface's first rxq slot in the stats maps.
+    base: usize,
     rxqs: Vec<Record>,
 }
 
 impl StatsRecord {
-    fn empty(num_rxqs: usize) -> Self {
+    fn empty(base: usize, num_rxqs: usize) -> Self {
         // not adding 2 causes memory corruption after bpf_map_lookup_elem.
         // why does libbpf lie about the amount bpf is going to write...
         let num_cpus = unsafe { libbpf::libbpf_num_possible_cpus() } as usize + 2;
         StatsRecord {
+            base,
             rxqs: (0..num_rxqs)
                 .map(|_| Record {
                     timestamp: std::time::Instant::now(),
                     cpu: (0..num_cpus).map(|_| Default::default()).collect(),
+                    ports: HashMap::new(),
                 })
                 .collect(),
         }
     }
 
-    fn update(&mut self, rx_queue_index_map: *mut libbpf::bpf_map) -> Result<(), StdError> {
+    fn update(
+        &mut self,
+        rx_queue_index_map: *mut libbpf::bpf_map,
+        port_stats_map: *mut libbpf::bpf_map,
+    ) -> Result<(), StdError> {
         let fd = unsafe { libbpf::bpf_map__fd(rx_queue_index_map) };
         assert!(fd > 0);
 
         for (i, rxq) in self.rxqs.iter_mut().enumerate() {
-            rxq.update(i, rx_queue_index_map)?;
+            rxq.update(self.base + i, rx_queue_index_map)?;
+        }
+
+        self.update_ports(port_stats_map)
+    }
+
+    fn update_ports(&mut self, port_stats_map: *mut libbpf::bpf_map) -> Result<(), StdError> {
+        let fd = map_fd(port_stats_map, "port_stats_map")?;
+        let num_cpus = unsafe { libbpf::libbpf_num_possible_cpus() } as usize;
+        for key in map_keys::<PortKey>(port_stats_map, "port_stats_map")? {
+            // other interfaces' slots are skipped.
+            let slot = (key.rxq as usize).wrapping_sub(self.base);
+            let rxq = match self.rxqs.get_mut(slot) {
+                Some(rxq) => rxq,
+                None => continue,
+            };
+
+            let mut percpu_recs = vec![PortRec::default(); num_cpus];
+            let ok = unsafe {
+                bpf::bpf_map_lookup_elem(
+                    fd,
+                    &key as *const _ as *const _,
+                    percpu_recs.as_mut_ptr() as *mut _,
+                )
+            };
+            if ok != 0 {
+                // evicted since we got the key.
+                continue;
+            }
+
+            rxq.ports.insert((key.proto, key.port), percpu_recs);
         }
 
         Ok(())
@@ -108,21 +298,215 @@ impl StatsRecord {
     pub fn get_rxq_cpu_port_count(&self) -> Vec<Vec<HashMap<u16, usize>>> {
         self.rxqs.iter().map(|r| r.get_cpu_port_count()).collect()
     }
+
+    // Vec<Vec<HashMap<(u8, u16), PortCounts>>> means: rxq_id -> cpu_id -> (proto, port) -> counts
+    pub fn get_rxq_cpu_proto_port_counts(&self) -> Vec<Vec<HashMap<(u8, u16), PortCounts>>> {
+        self.rxqs
+            .iter()
+            .map(|r| r.get_cpu_proto_port_counts())
+            .collect()
+    }
+
+    // Vec<Vec<HashMap<u16, usize>>> means: rxq_id -> cpu_id -> port -> bytes
+    pub fn get_rxq_cpu_port_bytes(&self) -> Vec<Vec<HashMap<u16, usize>>> {
+        self.rxqs.iter().map(|r| r.get_cpu_port_bytes()).collect()
+    }
+
+    // Vec<Vec<IpCounts>> means: rxq_id -> cpu_id -> counts
+    pub fn get_rxq_cpu_ip_counts(&self) -> Vec<Vec<IpCounts>> {
+        self.rxqs.iter().map(|r| r.get_cpu_ip_counts()).collect()
+    }
+}
+
+/// How the hash of a shard key picks one of the shard ports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShardMode {
+    /// `hash % num_shards`. Changing the set of shards remaps almost every key.
+    Modulo,
+    /// Consistent hashing with a [`maglev`] lookup table. Changing the set of shards remaps
+    /// about 1/N of the keys.
+    Maglev,
+}
+
+/// How the XDP program hashes a rule's shard key. [`hash`] has the same functions, to predict
+/// which shard a key goes to.
+///
+/// TCP connections are always sharded on an FNV-1a hash of their 4-tuple.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShardHash {
+    /// 64-bit FNV-1a. This is the default.
+    #[default]
+    Fnv1a,
+    /// SipHash-1-3 with a secret 128-bit key, so that clients can't pick keys that all land on
+    /// one shard.
+    SipHash13([u64; 2]),
+    /// CRC-32 (IEEE).
+    Crc32,
+    /// xxHash32 with a seed.
+    XxHash32(u32),
+    /// No hashing: the key read as a big-endian integer. Keys longer than 8 bytes use their last
+    /// 8 bytes.
+    Identity,
+    /// No hashing: the key read as a little-endian integer. Keys longer than 8 bytes use their
+    /// first 8 bytes.
+    IdentityLe,
+}
+
+impl ShardHash {
+    /// Hash a shard key (see [`hash::shard_key`]) the way the XDP program does.
+    pub fn hash(&self, key: &[u8]) -> u64 {
+        match *self {
+            ShardHash::Fnv1a => hash::fnv1a_64(key),
+            ShardHash::SipHash13(k) => hash::siphash13(k, key),
+            ShardHash::Crc32 => hash::crc32(key) as u64,
+            ShardHash::XxHash32(seed) => hash::xxhash32(seed, key) as u64,
+            ShardHash::Identity => hash::identity(key),
+            ShardHash::IdentityLe => hash::identity_le(key),
+        }
+    }
+
+    fn set_rules(self, rules: &mut ShardRules) {
+        let (hash, hash_key) = match self {
+            ShardHash::Fnv1a => (xdp_shard::HASH_FNV1A, [0, 0]),
+            ShardHash::SipHash13(k) => (xdp_shard::HASH_SIPHASH13, k),
+            ShardHash::Crc32 => (xdp_shard::HASH_CRC32, [0, 0]),
+            ShardHash::XxHash32(seed) => (xdp_shard::HASH_XXHASH32, [seed as u64, 0]),
+            ShardHash::Identity => (xdp_shard::HASH_IDENTITY, [0, 0]),
+            ShardHash::IdentityLe => (xdp_shard::HASH_IDENTITY_LE, [0, 0]),
+        };
+
+        rules.hash = hash as _;
+        rules.hash_key = hash_key;
+    }
+
+    fn from_rules(rules: &ShardRules) -> Option<Self> {
+        Some(match rules.hash as u32 {
+            xdp_shard::HASH_FNV1A => ShardHash::Fnv1a,
+            xdp_shard::HASH_SIPHASH13 => ShardHash::SipHash13(rules.hash_key),
+            xdp_shard::HASH_CRC32 => ShardHash::Crc32,
+            xdp_shard::HASH_XXHASH32 => ShardHash::XxHash32(rules.hash_key[0] as u32),
+            xdp_shard::HASH_IDENTITY => ShardHash::Identity,
+            xdp_shard::HASH_IDENTITY_LE => ShardHash::IdentityLe,
+            _ => return None,
+        })
+    }
+}
+
+/// Byte order of an integer shard key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyOrder {
+    BigEndian,
+    LittleEndian,
+}
+
+// how set_shards places keys on the shard ports.
+enum Placement<'a> {
+    Hash(ShardMode),
+    // sorted split points, and the key's byte order.
+    Range(&'a [u64], KeyOrder),
+}
+
+/// How the XDP program is attached to the interface.
+///
+/// There is no offload mode: NICs can't run the program's LRU and per-CPU maps or its redirects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XdpMode {
+    /// Native XDP, in the driver. Needs driver support.
+    Driver,
+    /// Generic XDP, after the kernel allocates an skb. Works on any interface, but is slower.
+    Skb,
+}
+
+impl XdpMode {
+    fn flags(self) -> u32 {
+        match self {
+            XdpMode::Driver => if_link::XDP_FLAGS_DRV_MODE,
+            XdpMode::Skb => if_link::XDP_FLAGS_SKB_MODE,
+        }
+    }
+}
+
+impl std::str::FromStr for XdpMode {
+    type Err = StdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "driver" | "native" => Ok(XdpMode::Driver),
+            "skb" | "generic" => Ok(XdpMode::Skb),
+            _ => Err(format!("Unknown xdp mode: {}", s))?,
+        }
+    }
+}
+
+// per-cpu queue size for cpu_map entries.
+const CPUMAP_QSIZE: u32 = 2048;
+
+// stats and xsks_map slots per interface: one per rx queue, and a shared one for the rest.
+const RXQ_SLOTS: u32 = xdp_shard::MAX_RXQs + 1;
+
+fn shard_ports(av: &AvailableShards) -> &[u16] {
+    &av.ports[..av.num as usize]
+}
+
+// a rule can list a port more than once.
+fn distinct_ports(ports: &[u16]) -> Vec<u16> {
+    let mut ports = ports.to_vec();
+    ports.sort_unstable();
+    ports.dedup();
+    ports
+}
+
+// pinned objects go in PIN_ROOT/<ifname>/
+const PIN_ROOT: &str = "/sys/fs/bpf/xdp-shard";
+
+fn pin_dir(interface_name: &str) -> String {
+    format!("{}/{}", PIN_ROOT, interface_name)
+}
+
+// An interface the program is loaded on. Its index in `BpfHandles::ifaces` is its ifindex_map
+// value.
+#[derive(Debug)]
+struct Iface {
+    ifindex: u32,
+    xdp_mode: XdpMode,
+    attached: bool,
 }
 
 /// Collection of handles to BPF objects.
 ///
-/// On drop, unloads the XDP program, via [`remove_xdp`].
+/// The XDP program and its maps can be shared by several interfaces (see
+/// [`load_on_interface_names_with_mode`]), so that they shard with the same rules.
+///
+/// On drop, unloads the XDP program, via [`remove_xdp`], unless it is pinned (see [`pin`]).
 #[derive(Debug)]
 pub struct BpfHandles {
     prog_fd: std::os::raw::c_int,
-    ifindex: u32,
+    ifaces: Vec<Iface>,
     bpf_obj: *mut libbpf::bpf_object,
     rx_queue_index_map: *mut libbpf::bpf_map,
+    port_stats_map: *mut libbpf::bpf_map,
     available_shards_map: *mut libbpf::bpf_map,
+    maglev_table_map: *mut libbpf::bpf_map,
+    range_splits_map: *mut libbpf::bpf_map,
+    key_override_map: *mut libbpf::bpf_map,
+    key_sketch_map: *mut libbpf::bpf_map,
+    hot_key_map: *mut libbpf::bpf_map,
+    sample_rate_map: *mut libbpf::bpf_map,
+    sample_map: *mut libbpf::bpf_map,
+    max_rules: usize,
+    cpu_map: *mut libbpf::bpf_map,
+    xsks_map: *mut libbpf::bpf_map,
+    drop_reason_map: *mut libbpf::bpf_map,
+    shard_stats_map: *mut libbpf::bpf_map,
+    rules: HashMap<u16, AvailableShards>,
+    egress: Vec<UnshardEgress>,
+    // port -> (orig_port its replies are unsharded to, number of rules using it).
+    egress_ports: HashMap<u16, (u16, usize)>,
+    pin_dir: Option<String>,
     num_rxqs: usize,
-    curr_record: StatsRecord,
-    prev_record: StatsRecord,
+    // one per interface.
+    curr_records: Vec<StatsRecord>,
+    prev_records: Vec<StatsRecord>,
 }
 
 impl BpfHandles {
@@ -132,41 +516,154 @@ impl BpfHandles {
     }
 
     /// Load xdp_port XDP program onto the given interface id.
+    ///
+    /// This attaches in [`XdpMode::Skb`].
     pub fn load_on_interface_id(interface_id: u32) -> Result<Self, StdError> {
-        let bpf_filename = concat!(env!("OUT_DIR"), "/xdp_shard.o\0");
+        BpfHandles::load_on_interface_id_with_mode(interface_id, XdpMode::Skb, false)
+    }
 
-        let bpf_filename_cstr = std::ffi::CStr::from_bytes_with_nul(bpf_filename.as_bytes())?;
-        let attr = libbpf::bpf_prog_load_attr {
-            file: bpf_filename_cstr.as_ptr(),
-            prog_type: libbpf::bpf_prog_type_BPF_PROG_TYPE_XDP,
-            prog_flags: 0,
-            expected_attach_type: libbpf::bpf_attach_type_BPF_CGROUP_INET_INGRESS,
-            ifindex: 0,
-            log_level: 0,
-        };
+    /// Like [`load_on_interface_name`], but attach in `mode`.
+    ///
+    /// If `fallback_to_skb` is set and attaching in [`XdpMode::Driver`] fails, this attaches in
+    /// [`XdpMode::Skb`] instead. [`xdp_mode`] returns the mode that was used.
+    pub fn load_on_interface_name_with_mode(
+        interface_name: &str,
+        mode: XdpMode,
+        fallback_to_skb: bool,
+    ) -> Result<Self, StdError> {
+        BpfHandles::load_on_interface_id_with_mode(
+            get_interface_id(interface_name)?,
+            mode,
+            fallback_to_skb,
+        )
+    }
 
-        let mut bpf_obj: *mut libbpf::bpf_object = std::ptr::null_mut();
-        let mut prog_fd = 0;
+    /// Like [`load_on_interface_id`], but attach in `mode`. See
+    /// [`load_on_interface_name_with_mode`].
+    pub fn load_on_interface_id_with_mode(
+        interface_id: u32,
+        mode: XdpMode,
+        fallback_to_skb: bool,
+    ) -> Result<Self, StdError> {
+        BpfHandles::load_on_interface_ids_with_mode(&[interface_id], mode, fallback_to_skb)
+    }
 
-        let ok = unsafe {
-            libbpf::bpf_prog_load_xattr(
-                &attr,
-                &mut bpf_obj as *mut *mut libbpf::bpf_object,
-                &mut prog_fd as *mut _,
-            )
-        };
-        if ok > 0 {
-            Err(format!("bpf_prog_load_xattr failed: {}", ok))?;
+    /// Load one xdp_port XDP program onto each of the given interfaces, sharing its maps.
+    ///
+    /// Sharding rules, AF_XDP sockets and drop counts are shared by all the interfaces, while
+    /// port and IP header stats are kept per interface (see [`get_interface_stats`]). Each
+    /// interface is attached in `mode`, falling back as in [`load_on_interface_name_with_mode`].
+    pub fn load_on_interface_names_with_mode(
+        interface_names: &[&str],
+        mode: XdpMode,
+        fallback_to_skb: bool,
+    ) -> Result<Self, StdError> {
+        let interface_ids = interface_names
+            .iter()
+            .map(|n| get_interface_id(n))
+            .collect::<Result<Vec<_>, _>>()?;
+        BpfHandles::load_on_interface_ids_with_mode(&interface_ids, mode, fallback_to_skb)
+    }
+
+    /// Like [`load_on_interface_names_with_mode`], with interface ids.
+    pub fn load_on_interface_ids_with_mode(
+        interface_ids: &[u32],
+        mode: XdpMode,
+        fallback_to_skb: bool,
+    ) -> Result<Self, StdError> {
+        if interface_ids.is_empty() || interface_ids.len() > xdp_shard::MAX_IFACES as usize {
+            Err(format!(
+                "Invalid number of interfaces (must be 1..={}): {:?}",
+                xdp_shard::MAX_IFACES,
+                interface_ids
+            ))?;
+        }
+
+        for (i, id) in interface_ids.iter().enumerate() {
+            if interface_ids[..i].contains(id) {
+                Err(format!("Duplicate interface: {}", id))?;
+            }
+        }
+
+        let bpf_filename = xdp_shard_obj();
+        let (bpf_obj, prog_fd) =
+            load_bpf_obj(bpf_filename, libbpf::bpf_prog_type_BPF_PROG_TYPE_XDP)?;
+
+        let ifaces = interface_ids
+            .iter()
+            .map(|&ifindex| Iface {
+                ifindex,
+                xdp_mode: mode,
+                attached: false,
+            })
+            .collect();
+        let mut this = BpfHandles::from_bpf_obj(bpf_obj, prog_fd, ifaces)?;
+        this.set_ifindices()?;
+        this.activate(fallback_to_skb)?;
+
+        this.refresh_stats()?;
+        Ok(this)
+    }
+
+    /// Open the XDP program and maps that [`pin`] left under `/sys/fs/bpf/xdp-shard/<ifname>/`.
+    ///
+    /// For a program loaded onto several interfaces, `interface_name` is the first of them. The
+    /// program stays attached, and its rules and counters carry on where they were. The
+    /// returned handle is pinned too, so it does not remove the program on drop either.
+    pub fn open_pinned(interface_name: &str) -> Result<Self, StdError> {
+        let pin_dir = pin_dir(interface_name);
+        let prog_fd = obj_get(&format!("{}/prog", pin_dir))?;
+
+        // open, but don't load, the object file: this gets us the map definitions, and then each
+        // map can use its pinned instance instead of creating a new one.
+        let bpf_filename = xdp_shard_obj();
+        let bpf_filename_cstr = std::ffi::CStr::from_bytes_with_nul(bpf_filename.as_bytes())?;
+        let bpf_obj = unsafe { libbpf::bpf_object__open(bpf_filename_cstr.as_ptr()) };
+        if bpf_obj.is_null() || unsafe { libbpf::libbpf_get_error(bpf_obj as *const _) } != 0 {
+            Err(format!("bpf_object__open {:?} failed", bpf_filename_cstr))?;
         }
 
-        if prog_fd == 0 {
-            Err(format!("bpf_prog_load_xattr returned null fd"))?;
+        let mut map = unsafe { libbpf::bpf_map__next(std::ptr::null(), bpf_obj) };
+        while !map.is_null() {
+            let name = unsafe { std::ffi::CStr::from_ptr(libbpf::bpf_map__name(map)) };
+            let map_fd = obj_get(&format!("{}/{}", pin_dir, name.to_str()?))?;
+            // reuse_fd dups the fd.
+            let ok = unsafe { libbpf::bpf_map__reuse_fd(map, map_fd) };
+            unsafe { nix::libc::close(map_fd) };
+            if ok < 0 {
+                Err(format!("bpf_map__reuse_fd for {:?} failed: {}", name, ok))?;
+            }
+
+            map = unsafe { libbpf::bpf_map__next(map, bpf_obj) };
         }
 
-        if prog_fd < 0 {
-            Err(format!("bpf_prog_load_xattr returned bad fd: {}", prog_fd))?;
+        let ifaces = read_ifindices(bpf_obj)?
+            .into_iter()
+            .map(|ifindex| {
+                Ok(Iface {
+                    ifindex,
+                    xdp_mode: get_xdp_mode(ifindex)?,
+                    attached: true,
+                })
+            })
+            .collect::<Result<Vec<_>, StdError>>()?;
+        if ifaces.is_empty() {
+            Err(format!("No interfaces in pinned ifindex_map: {}", pin_dir))?;
         }
 
+        let mut this = BpfHandles::from_bpf_obj(bpf_obj, prog_fd, ifaces)?;
+        this.pin_dir = Some(pin_dir);
+        this.load_rules()?;
+
+        this.refresh_stats()?;
+        Ok(this)
+    }
+
+    fn from_bpf_obj(
+        bpf_obj: *mut libbpf::bpf_object,
+        prog_fd: std::os::raw::c_int,
+        ifaces: Vec<Iface>,
+    ) -> Result<Self, StdError> {
         let rx_queue_index_map = get_map_by_name("rx_queue_index_map\0", bpf_obj)?;
         let num_rxqs = unsafe {
             let ptr = libbpf::bpf_map__def(rx_queue_index_map);
@@ -176,27 +673,155 @@ impl BpfHandles {
                 ))?;
             }
 
-            (*ptr).max_entries
+            // the map has RXQ_SLOTS for each of MAX_IFACES.
+            (*ptr).max_entries / xdp_shard::MAX_IFACES
         };
 
+        let port_stats_map = get_map_by_name("port_stats_map\0", bpf_obj)?;
         let available_shards_map = get_map_by_name("available_shards_map\0", bpf_obj)?;
+        let max_rules = unsafe {
+            let ptr = libbpf::bpf_map__def(available_shards_map);
+            if ptr.is_null() {
+                Err(String::from(
+                    "Could not get bpf_map_def for available_shards_map",
+                ))?;
+            }
+
+            (*ptr).max_entries
+        };
+        let maglev_table_map = get_map_by_name("maglev_table_map\0", bpf_obj)?;
+        let range_splits_map = get_map_by_name("range_splits_map\0", bpf_obj)?;
+        let key_override_map = get_map_by_name("key_override_map\0", bpf_obj)?;
+        let key_sketch_map = get_map_by_name("key_sketch_map\0", bpf_obj)?;
+        let hot_key_map = get_map_by_name("hot_key_map\0", bpf_obj)?;
+        let sample_rate_map = get_map_by_name("sample_rate_map\0", bpf_obj)?;
+        let sample_map = get_map_by_name("sample_map\0", bpf_obj)?;
+        let cpu_map = get_map_by_name("cpu_map\0", bpf_obj)?;
+        let xsks_map = get_map_by_name("xsks_map\0", bpf_obj)?;
+        let drop_reason_map = get_map_by_name("drop_reason_map\0", bpf_obj)?;
+        let shard_stats_map = get_map_by_name("shard_stats_map\0", bpf_obj)?;
+
+        let records = || -> Vec<StatsRecord> {
+            (0..ifaces.len())
+                .map(|i| StatsRecord::empty(i * num_rxqs as usize, num_rxqs as _))
+                .collect()
+        };
 
-        let mut this = BpfHandles {
+        Ok(BpfHandles {
             prog_fd,
-            ifindex: interface_id,
+            curr_records: records(),
+            prev_records: records(),
+            ifaces,
             bpf_obj,
             rx_queue_index_map,
+            port_stats_map,
             available_shards_map,
+            maglev_table_map,
+            range_splits_map,
+            key_override_map,
+            key_sketch_map,
+            hot_key_map,
+            sample_rate_map,
+            sample_map,
+            max_rules: max_rules as _,
+            cpu_map,
+            xsks_map,
+            drop_reason_map,
+            shard_stats_map,
+            rules: HashMap::new(),
+            egress: vec![],
+            egress_ports: HashMap::new(),
+            pin_dir: None,
             num_rxqs: num_rxqs as _,
-            curr_record: StatsRecord::empty(num_rxqs as _),
-            prev_record: StatsRecord::empty(num_rxqs as _),
+        })
+    }
+
+    /// Pin the XDP program and its maps under `/sys/fs/bpf/xdp-shard/<ifname>/`, so that sharding
+    /// and counters survive this process. `<ifname>` is the first interface the program was
+    /// loaded on.
+    ///
+    /// A pinned handle leaves the program attached on drop, and a later process can pick it up
+    /// again with [`open_pinned`]. The TC egress program from [`unshard_egress`] would not outlive
+    /// the handle, so this fails if egress unsharding is on.
+    pub fn pin(&mut self) -> Result<(), StdError> {
+        if self.pin_dir.is_some() {
+            return Ok(());
+        }
+
+        if !self.egress.is_empty() {
+            Err(String::from(
+                "Cannot pin while egress unsharding is on: it would stop on drop",
+            ))?;
+        }
+
+        let pin_dir = pin_dir(&get_interface_name(self.ifaces[0].ifindex)?);
+        std::fs::create_dir_all(&pin_dir)?;
+        let prog_path = format!("{}/prog", pin_dir);
+        obj_pin(self.prog_fd, &prog_path)?;
+
+        let pin_dir_cstr = std::ffi::CString::new(pin_dir.clone())?;
+        let ok = unsafe { libbpf::bpf_object__pin_maps(self.bpf_obj, pin_dir_cstr.as_ptr()) };
+        if ok < 0 {
+            std::fs::remove_file(&prog_path)?;
+            Err(format!("bpf_object__pin_maps {} failed: {}", pin_dir, ok))?;
+        }
+
+        self.pin_dir = Some(pin_dir);
+        Ok(())
+    }
+
+    /// Remove the pins, so that this handle removes the program on drop again.
+    pub fn unpin(&mut self) -> Result<(), StdError> {
+        let pin_dir = match self.pin_dir.take() {
+            Some(d) => d,
+            None => return Ok(()),
         };
 
-        this.set_ifindex()?;
-        this.activate()?;
+        let pin_dir_cstr = std::ffi::CString::new(pin_dir.clone())?;
+        let ok = unsafe { libbpf::bpf_object__unpin_maps(self.bpf_obj, pin_dir_cstr.as_ptr()) };
+        if ok < 0 {
+            Err(format!("bpf_object__unpin_maps {} failed: {}", pin_dir, ok))?;
+        }
+
+        std::fs::remove_file(format!("{}/prog", pin_dir))?;
+        std::fs::remove_dir(&pin_dir)?;
+        Ok(())
+    }
+
+    // read back the rules of a pinned instance.
+    fn load_rules(&mut self) -> Result<(), StdError> {
+        let fd = map_fd(self.available_shards_map, "available_shards_map")?;
+        for key in map_keys::<u16>(self.available_shards_map, "available_shards_map")? {
+            let mut av = AvailableShards::default();
+            let ok = unsafe {
+                bpf::bpf_map_lookup_elem(
+                    fd,
+                    &key as *const _ as *const _,
+                    &mut av as *mut _ as *mut _,
+                )
+            };
+            if ok != 0 {
+                Err(format!(
+                    "Could not bpf_map_lookup_elem for available_shards_map: {}",
+                    key
+                ))?;
+            }
 
-        this.curr_record.update(rx_queue_index_map)?;
-        Ok(this)
+            for port in distinct_ports(shard_ports(&av)) {
+                self.ref_egress_port(port, key)?;
+            }
+
+            self.rules.insert(key, av);
+        }
+
+        // pinned keys' ports are unsharded too.
+        for okey in map_keys::<OverrideKey>(self.key_override_map, "key_override_map")? {
+            if let Some(port) = self.pinned_port(&okey)? {
+                self.ref_egress_port(port, okey.port)?;
+            }
+        }
+
+        Ok(())
     }
 
     /// Define the set of sharding ports.
@@ -204,6 +829,16 @@ impl BpfHandles {
     /// By default, the shards map is empty and xdp_port will not rewrite port numbers, only log.
     /// Setting this will define a set of ports that xdp_port will shard between.
     ///
+    /// The shard key is the `field_size` bytes at `msg_offset` in the payload. TCP connections
+    /// are instead sharded on their 4-tuple when the SYN arrives, and the rest of the connection
+    /// follows the SYN.
+    ///
+    /// The key is hashed with [`ShardHash::Fnv1a`] unless [`set_shard_hash`] picked another hash
+    /// for `orig_port`.
+    ///
+    /// A shard port can only be used by one `orig_port`, since [`unshard_egress`] has to know
+    /// which port to rewrite its replies to.
+    ///
     /// Note: It is not safe to call this concurrently, so it takes `&mut` self even though it would
     /// compile (unsafely) taking `&self`.
     pub fn shard_ports(
@@ -213,118 +848,1253 @@ impl BpfHandles {
         msg_offset: u8,
         field_size: u8,
     ) -> Result<(), StdError> {
-        if ports.len() > 16 {
-            Err(format!(
-                "Too many ports to shard (max 16): {:?}",
-                ports.len()
-            ))?;
+        self.shard_ports_composite(
+            orig_port,
+            ports,
+            &[(msg_offset, field_size)],
+            ShardMode::Modulo,
+        )
+    }
+
+    /// Like [`shard_ports`], but the shard key is made of several payload fields.
+    ///
+    /// `fields` is a list of `(msg_offset, field_size)` pieces, hashed together in order. `mode`
+    /// picks how the key's hash is mapped onto `ports`.
+    pub fn shard_ports_composite(
+        &mut self,
+        orig_port: u16,
+        ports: &[u16],
+        fields: &[(u8, u8)],
+        mode: ShardMode,
+    ) -> Result<(), StdError> {
+        let ports: Vec<(u16, u32)> = ports.iter().map(|&p| (p, 1)).collect();
+        self.set_shards(orig_port, &ports, fields, Placement::Hash(mode))
+    }
+
+    /// Like [`shard_ports_composite`], but each shard port gets a share of the keys proportional
+    /// to its weight.
+    ///
+    /// `ports` is a list of `(port, weight)`. This always uses [`ShardMode::Maglev`], with a
+    /// weighted lookup table.
+    pub fn shard_ports_weighted(
+        &mut self,
+        orig_port: u16,
+        ports: &[(u16, u32)],
+        fields: &[(u8, u8)],
+    ) -> Result<(), StdError> {
+        if ports.iter().all(|&(_, w)| w == 0) {
+            Err(format!("No shard port has a nonzero weight: {:?}", ports))?;
         }
 
-        if field_size != 4 {
+        self.set_shards(orig_port, ports, fields, Placement::Hash(ShardMode::Maglev))
+    }
+
+    /// Partition the shard key by range instead of hashing it.
+    ///
+    /// The key, `fields` concatenated as in [`shard_ports_composite`], is read as an unsigned
+    /// integer in byte order `order`, so it can be at most 8 bytes. `splits` are the sorted
+    /// split points between consecutive `ports`: `ports[i]` gets the keys in
+    /// `splits[i - 1]..splits[i]`, the first port everything below `splits[0]`, and the last
+    /// port everything from the last split point up. [`hash::range_index`] gives the same
+    /// placement.
+    ///
+    /// TCP connections have no key to compare with `splits`, so they are spread over `ports` by
+    /// the hash of their 4-tuple modulo the number of ports.
+    pub fn shard_ports_range(
+        &mut self,
+        orig_port: u16,
+        ports: &[u16],
+        splits: &[u64],
+        fields: &[(u8, u8)],
+        order: KeyOrder,
+    ) -> Result<(), StdError> {
+        if ports.is_empty() || splits.len() != ports.len() - 1 {
             Err(format!(
-                "field_size != 4 currently doesn't pass the bpf verifier :("
+                "Need one split point between each pair of ports: {:?}, {:?}",
+                ports, splits
             ))?;
         }
 
-        let mut av = AvailableShards {
-            num: ports.len() as _,
-            ports: [0u16; 16],
-            rules: ShardRules {
-                msg_offset,
-                field_size,
-            },
-        };
-
-        av.ports[0..ports.len()].copy_from_slice(ports);
-
-        // now set it
-        let fd = unsafe { libbpf::bpf_map__fd(self.available_shards_map) };
-        if fd < 0 {
-            Err(format!("available_shards_map returned bad fd: {}", fd))?;
+        if splits.windows(2).any(|w| w[0] >= w[1]) {
+            Err(format!("Split points are not sorted: {:?}", splits))?;
         }
 
-        let ok = unsafe {
-            bpf::bpf_map_update_elem(
-                fd,
-                &orig_port as *const _ as *const _,
-                &av as *const _ as *const _,
-                0,
-            )
-        };
-        if ok < 0 {
-            let errno = nix::errno::Errno::last();
+        let key_len: usize = fields.iter().map(|&(_, size)| size as usize).sum();
+        if key_len > 8 {
             Err(format!(
-                "available_shards_map update elem failed: {}",
-                errno
+                "Range key is too long for an integer (max 8 bytes): {}",
+                key_len
             ))?;
         }
 
-        Ok(())
-    }
-
-    /// Query cpu-rxq-port records.
-    ///
-    /// Returns (curr_record, prev_record) tuple. prev_record is equal to the previous call's
-    /// curr_record.
-    pub fn get_stats(&mut self) -> Result<(&StatsRecord, &StatsRecord), StdError> {
-        std::mem::swap(&mut self.prev_record, &mut self.curr_record);
-        self.curr_record = StatsRecord::empty(self.num_rxqs);
-        self.curr_record.update(self.rx_queue_index_map)?;
-        Ok((&self.curr_record, &self.prev_record))
+        let ports: Vec<(u16, u32)> = ports.iter().map(|&p| (p, 1)).collect();
+        self.set_shards(orig_port, &ports, fields, Placement::Range(splits, order))
     }
 
-    fn set_ifindex(&mut self) -> Result<(), StdError> {
-        let ifindex_map = get_map_by_name("ifindex_map\0", self.bpf_obj)?;
-
-        let ifindex_map_fd = unsafe { libbpf::bpf_map__fd(ifindex_map) };
-        if ifindex_map_fd < 0 {
+    fn set_shards(
+        &mut self,
+        orig_port: u16,
+        ports: &[(u16, u32)],
+        fields: &[(u8, u8)],
+        placement: Placement,
+    ) -> Result<(), StdError> {
+        let mut av = AvailableShards::default();
+        if ports.len() > av.ports.len() {
             Err(format!(
-                "ifindex_map_fd returned bad fd: {}",
-                ifindex_map_fd
+                "Too many ports to shard (max {}): {:?}",
+                av.ports.len(),
+                ports.len()
             ))?;
         }
 
-        let key = 0;
-        let ifindex = self.ifindex as i32;
-        let ok = unsafe {
-            bpf::bpf_map_update_elem(
-                ifindex_map_fd,
-                &key as *const _ as *const _,
-                &ifindex as *const _ as *const _,
-                0,
-            )
-        };
-        if ok < 0 {
-            Err(format!("ifindex_map bpf_map_update_elem failed: {}", ok))?;
+        if !self.rules.contains_key(&orig_port) && self.rules.len() >= self.max_rules {
+            Err(format!(
+                "Too many sharded ports (max {}): {:?}",
+                self.max_rules,
+                self.rules.keys().collect::<Vec<_>>()
+            ))?;
         }
 
-        Ok(())
-    }
+        // checked here as well as in ref_egress_port, so that a rejected rule changes nothing.
+        for &(port, _) in ports {
+            match self.egress_ports.get(&port) {
+                Some(&(other, _)) if other != orig_port => Err(format!(
+                    "Port {} is already a shard port of, or pinned for, {}",
+                    port, other
+                ))?,
+                _ => (),
+            }
+        }
 
-    fn activate(&mut self) -> Result<(), StdError> {
-        let xdp_flags = if_link::XDP_FLAGS_SKB_MODE | if_link::XDP_FLAGS_UPDATE_IF_NOEXIST;
-        let ok = unsafe { libbpf::bpf_set_link_xdp_fd(self.ifindex as _, self.prog_fd, xdp_flags) };
-        if ok < 0 {
-            Err(format!("bpf_set_link_xdp_fd failed: {}", ok))?;
+        if fields.is_empty() || fields.len() as u32 > xdp_shard::MAX_KEY_FIELDS {
+            Err(format!(
+                "Invalid number of key fields (must be 1..={}): {}",
+                xdp_shard::MAX_KEY_FIELDS,
+                fields.len()
+            ))?;
         }
 
-        Ok(())
-    }
-}
+        let mut rules = ShardRules {
+            num_fields: fields.len() as _,
+            ..Default::default()
+        };
+
+        for (i, &(msg_offset, field_size)) in fields.iter().enumerate() {
+            if field_size < 1 || field_size as u32 > xdp_shard::MAX_FIELD_SIZE {
+                Err(format!(
+                    "Invalid field_size (must be 1..={}): {}",
+                    xdp_shard::MAX_FIELD_SIZE,
+                    field_size
+                ))?;
+            }
+
+            rules.fields[i] = ShardField {
+                msg_offset,
+                field_size,
+            };
+        }
+
+        match (&placement, self.rules.get(&orig_port)) {
+            (Placement::Range(_, KeyOrder::BigEndian), _) => {
+                ShardHash::Identity.set_rules(&mut rules)
+            }
+            (Placement::Range(_, KeyOrder::LittleEndian), _) => {
+                ShardHash::IdentityLe.set_rules(&mut rules)
+            }
+            // a range rule's identity hash doesn't carry over.
+            (_, Some(old)) if old.mode as u32 != xdp_shard::SHARD_MODE_RANGE => {
+                rules.hash = old.rules.hash;
+                rules.hash_key = old.rules.hash_key;
+            }
+            _ => ShardHash::default().set_rules(&mut rules),
+        }
+
+        av.num = ports.len() as _;
+        av.rules = rules;
+        av.mode = xdp_shard::SHARD_MODE_MODULO as _;
+
+        for (slot, &(port, _)) in av.ports.iter_mut().zip(ports) {
+            *slot = port;
+        }
+
+        match placement {
+            Placement::Hash(ShardMode::Modulo) => (),
+            Placement::Hash(ShardMode::Maglev) => {
+                if ports.is_empty() {
+                    Err(String::from("Maglev sharding needs at least one port"))?;
+                }
+
+                // the table has to be in place before the rule points to it.
+                let table = maglev::populate(ports, xdp_shard::MAGLEV_TABLE_SIZE as _);
+                update_elem(
+                    self.maglev_table_map,
+                    "maglev_table_map",
+                    &orig_port,
+                    &table[..],
+                )?;
+                av.mode = xdp_shard::SHARD_MODE_MAGLEV as _;
+            }
+            Placement::Range(splits, _) => {
+                // same for the split points. unused ones stay at u64::MAX.
+                let mut padded = [u64::MAX; xdp_shard::MAX_SHARDS as usize - 1];
+                padded[..splits.len()].copy_from_slice(splits);
+                update_elem(
+                    self.range_splits_map,
+                    "range_splits_map",
+                    &orig_port,
+                    &padded[..],
+                )?;
+                av.mode = xdp_shard::SHARD_MODE_RANGE as _;
+            }
+        }
+
+        // shard indices now mean different ports, so start the counts over.
+        let num_cpus = unsafe { libbpf::libbpf_num_possible_cpus() } as usize;
+        let stats = vec![ShardStatsRec::default(); num_cpus];
+        update_elem(
+            self.shard_stats_map,
+            "shard_stats_map",
+            &orig_port,
+            &stats[..],
+        )?;
+        // same for the key counts, if they are tracked.
+        if self.tracks_hot_keys(orig_port) {
+            self.reset_key_sketch(orig_port)?;
+            av.track_keys = 1;
+        }
+
+        // now set it
+        update_elem(
+            self.available_shards_map,
+            "available_shards_map",
+            &orig_port,
+            &av,
+        )?;
+
+        // take the new ports before dropping the old ones, so the ports in both stay put.
+        let old_ports = match self.rules.get(&orig_port) {
+            Some(old) => distinct_ports(shard_ports(old)),
+            None => vec![],
+        };
+        for port in distinct_ports(shard_ports(&av)) {
+            self.ref_egress_port(port, orig_port)?;
+        }
+
+        for port in old_ports {
+            self.unref_egress_port(port)?;
+        }
+
+        self.rules.insert(orig_port, av);
+        Ok(())
+    }
+
+    // the egress program rewrites replies from port to orig_port while anything uses port.
+    fn ref_egress_port(&mut self, port: u16, orig_port: u16) -> Result<(), StdError> {
+        match self.egress_ports.get_mut(&port) {
+            Some((other, refs)) if *other == orig_port => {
+                *refs += 1;
+                return Ok(());
+            }
+            Some((other, _)) => Err(format!(
+                "Port {} is already a shard port of, or pinned for, {}",
+                port, other
+            ))?,
+            None => (),
+        }
+
+        for egress in self.egress.iter() {
+            egress.set(port, orig_port)?;
+        }
+
+        self.egress_ports.insert(port, (orig_port, 1));
+        Ok(())
+    }
+
+    fn unref_egress_port(&mut self, port: u16) -> Result<(), StdError> {
+        let refs = match self.egress_ports.get_mut(&port) {
+            Some((_, refs)) => {
+                *refs -= 1;
+                *refs
+            }
+            None => return Ok(()),
+        };
+
+        if refs == 0 {
+            self.egress_ports.remove(&port);
+            for egress in self.egress.iter() {
+                egress.remove(port)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Redirect each shard's packets to a CPU, so that a server thread pinned there gets them.
+    ///
+    /// `cpus[i]` is the CPU for the i-th port of the last `shard_ports*` call for `orig_port`, so
+    /// this must be called again after changing the shards. An empty `cpus` turns redirection
+    /// off. Redirection uses a `BPF_MAP_TYPE_CPUMAP`, so the packets are handed to the network
+    /// stack on the target CPU.
+    ///
+    /// This replaces delivery to AF_XDP sockets (see [`xsk::XskShardServer`]).
+    ///
+    /// Before Linux 5.15, generic XDP cannot redirect into a CPUMAP and the packets would be
+    /// dropped, so on those kernels this fails if the program is attached in [`XdpMode::Skb`] on
+    /// any interface.
+    pub fn redirect_shards_to_cpus(
+        &mut self,
+        orig_port: u16,
+        cpus: &[u32],
+    ) -> Result<(), StdError> {
+        let mut av = match self.rules.get(&orig_port) {
+            Some(av) => *av,
+            None => Err(format!("Port is not sharded: {}", orig_port))?,
+        };
+
+        if !cpus.is_empty() && cpus.len() != av.num as usize {
+            Err(format!("Need one cpu per shard ({}): {:?}", av.num, cpus))?;
+        }
+
+        let skb_mode = self.ifaces.iter().any(|i| i.xdp_mode == XdpMode::Skb);
+        match kernel_version() {
+            Some(v) if !cpus.is_empty() && skb_mode && v < (5, 15) => Err(format!(
+                "Cannot redirect to cpus from skb mode xdp before Linux 5.15: running {}.{}",
+                v.0, v.1
+            ))?,
+            _ => (),
+        }
+
+        let num_cpus = unsafe { libbpf::libbpf_num_possible_cpus() } as u32;
+        for &cpu in cpus {
+            if cpu >= num_cpus || cpu >= xdp_shard::MAX_CPUS {
+                Err(format!("Invalid cpu: {}", cpu))?;
+            }
+
+            update_elem(self.cpu_map, "cpu_map", &cpu, &CPUMAP_QSIZE)?;
+        }
+
+        for (slot, &cpu) in av.cpus.iter_mut().zip(cpus) {
+            *slot = cpu as _;
+        }
+
+        let deliver = if cpus.is_empty() {
+            xdp_shard::DELIVER_STACK
+        } else {
+            xdp_shard::DELIVER_CPU
+        };
+        self.set_deliver(orig_port, av, deliver)
+    }
+
+    /// Hash `orig_port`'s shard key with `hash`.
+    ///
+    /// `orig_port` must already be sharded, and not with [`shard_ports_range`]. Unlike
+    /// [`redirect_shards_to_cpus`], the choice stays when the shards change.
+    pub fn set_shard_hash(&mut self, orig_port: u16, hash: ShardHash) -> Result<(), StdError> {
+        let mut av = match self.rules.get(&orig_port) {
+            Some(av) => *av,
+            None => Err(format!("Port is not sharded: {}", orig_port))?,
+        };
+
+        if av.mode as u32 == xdp_shard::SHARD_MODE_RANGE {
+            Err(format!(
+                "Port is range partitioned, its key is not hashed: {}",
+                orig_port
+            ))?;
+        }
+
+        hash.set_rules(&mut av.rules);
+        update_elem(
+            self.available_shards_map,
+            "available_shards_map",
+            &orig_port,
+            &av,
+        )?;
+
+        self.rules.insert(orig_port, av);
+        Ok(())
+    }
+
+    /// The hash of `orig_port`'s shard key, if it is sharded.
+    pub fn shard_hash(&self, orig_port: u16) -> Option<ShardHash> {
+        self.rules
+            .get(&orig_port)
+            .and_then(|av| ShardHash::from_rules(&av.rules))
+    }
+
+    /// Send packets for `orig_port` whose shard key is `key` to `port`, instead of hashing them.
+    ///
+    /// This is for hot keys that would swamp their shard: `port` can be a dedicated shard that
+    /// is not one of `orig_port`'s shard ports. `key` is the rule's fields concatenated in order
+    /// (see [`hash::shard_key`]), so `orig_port` must already be sharded, and its keys have to be
+    /// pinned again if the field sizes change. Pinned keys always go to the kernel stack, even if
+    /// `orig_port` is delivered to CPUs or AF_XDP sockets. TCP connections are not affected.
+    ///
+    /// As with shard ports, [`unshard_egress`] rewrites replies from `port` to `orig_port`, so
+    /// `port` can't be a shard port of another `orig_port`.
+    pub fn pin_key(&mut self, orig_port: u16, key: &[u8], port: u16) -> Result<(), StdError> {
+        let okey = self.override_key(orig_port, key)?;
+        let old_port = self.pinned_port(&okey)?;
+        if old_port == Some(port) {
+            return Ok(());
+        }
+
+        self.ref_egress_port(port, orig_port)?;
+        let rec = OverrideRec {
+            port,
+            ..Default::default()
+        };
+        if let Err(e) = update_elem(self.key_override_map, "key_override_map", &okey, &rec) {
+            self.unref_egress_port(port)?;
+            return Err(e);
+        }
+
+        if let Some(old_port) = old_port {
+            self.unref_egress_port(old_port)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hash `key` again, undoing [`pin_key`].
+    pub fn unpin_key(&mut self, orig_port: u16, key: &[u8]) -> Result<(), StdError> {
+        let okey = self.override_key(orig_port, key)?;
+        let old_port = match self.pinned_port(&okey)? {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        delete_elem(self.key_override_map, "key_override_map", &okey)?;
+        self.unref_egress_port(old_port)
+    }
+
+    // the port okey is pinned to, if any.
+    fn pinned_port(&self, okey: &OverrideKey) -> Result<Option<u16>, StdError> {
+        let fd = map_fd(self.key_override_map, "key_override_map")?;
+        let mut rec = OverrideRec::default();
+        let ok = unsafe {
+            bpf::bpf_map_lookup_elem(
+                fd,
+                okey as *const _ as *const _,
+                &mut rec as *mut _ as *mut _,
+            )
+        };
+        Ok(if ok == 0 { Some(rec.port) } else { None })
+    }
+
+    /// The keys pinned for `orig_port`, with their hit counts.
+    pub fn pinned_keys(&self, orig_port: u16) -> Result<Vec<PinnedKey>, StdError> {
+        let sizes = self.key_field_sizes(orig_port)?;
+        let fd = map_fd(self.key_override_map, "key_override_map")?;
+        let mut pinned = vec![];
+        for key in map_keys::<OverrideKey>(self.key_override_map, "key_override_map")? {
+            if key.port != orig_port {
+                continue;
+            }
+
+            let mut rec = OverrideRec::default();
+            let ok = unsafe {
+                bpf::bpf_map_lookup_elem(
+                    fd,
+                    &key as *const _ as *const _,
+                    &mut rec as *mut _ as *mut _,
+                )
+            };
+            if ok != 0 {
+                // unpinned since we got the key.
+                continue;
+            }
+
+            pinned.push(PinnedKey {
+                key: sizes
+                    .iter()
+                    .zip(key.fields.iter())
+                    .flat_map(|(&size, slot)| slot[..size].iter().copied())
+                    .collect(),
+                port: rec.port,
+                hits: rec.hits as _,
+            });
+        }
+
+        Ok(pinned)
+    }
+
+    /// Count `orig_port`'s shard keys, so that [`hot_keys`] can find the most frequent ones.
+    ///
+    /// This is off by default, since every packet then updates a count-min sketch shared by all
+    /// CPUs. It stays on when `orig_port`'s shards change, but the counts start over.
+    pub fn track_hot_keys(&mut self, orig_port: u16, on: bool) -> Result<(), StdError> {
+        let mut av = match self.rules.get(&orig_port) {
+            Some(av) => *av,
+            None => Err(format!("Port is not sharded: {}", orig_port))?,
+        };
+
+        if (av.track_keys != 0) == on {
+            return Ok(());
+        }
+
+        // the sketch has to be in place before the rule uses it, and stay until it doesn't.
+        if on {
+            self.reset_key_sketch(orig_port)?;
+        }
+
+        av.track_keys = on as _;
+        update_elem(
+            self.available_shards_map,
+            "available_shards_map",
+            &orig_port,
+            &av,
+        )?;
+        self.rules.insert(orig_port, av);
+
+        if !on {
+            delete_elem(self.key_sketch_map, "key_sketch_map", &orig_port)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether [`track_hot_keys`] turned hot key tracking on for `orig_port`.
+    pub fn tracks_hot_keys(&self, orig_port: u16) -> bool {
+        matches!(self.rules.get(&orig_port), Some(av) if av.track_keys != 0)
+    }
+
+    fn reset_key_sketch(&mut self, orig_port: u16) -> Result<(), StdError> {
+        let sketch = vec![0u64; (xdp_shard::SKETCH_DEPTH * xdp_shard::SKETCH_WIDTH) as usize];
+        update_elem(
+            self.key_sketch_map,
+            "key_sketch_map",
+            &orig_port,
+            &sketch[..],
+        )
+    }
+
+    /// The (at most) `k` most frequent shard keys of `orig_port` since [`track_hot_keys`] turned
+    /// tracking on or its shards were last set, most frequent first.
+    ///
+    /// The XDP program counts every key in a count-min sketch, and keeps recently seen keys as
+    /// candidates once they have been counted `SKETCH_HOT_MIN` times. Keys are the rule's fields
+    /// concatenated in order, as in [`pin_key`]. TCP connections are not counted.
+    pub fn hot_keys(&self, orig_port: u16, k: usize) -> Result<Vec<HotKey>, StdError> {
+        let sizes = self.key_field_sizes(orig_port)?;
+        if !self.tracks_hot_keys(orig_port) {
+            Err(format!("Hot keys are not tracked for port {}", orig_port))?;
+        }
+
+        let fd = map_fd(self.key_sketch_map, "key_sketch_map")?;
+        let width = xdp_shard::SKETCH_WIDTH as usize;
+        let mut sketch = vec![0u64; xdp_shard::SKETCH_DEPTH as usize * width];
+        let ok = unsafe {
+            bpf::bpf_map_lookup_elem(
+                fd,
+                &orig_port as *const _ as *const _,
+                sketch.as_mut_ptr() as *mut _,
+            )
+        };
+        if ok != 0 {
+            Err(format!(
+                "Could not bpf_map_lookup_elem for key_sketch_map: {}",
+                orig_port
+            ))?;
+        }
+
+        let mut hot = vec![];
+        for key in map_keys::<OverrideKey>(self.hot_key_map, "hot_key_map")? {
+            if key.port != orig_port {
+                continue;
+            }
+
+            let key: Vec<u8> = sizes
+                .iter()
+                .zip(key.fields.iter())
+                .flat_map(|(&size, slot)| slot[..size].iter().copied())
+                .collect();
+
+            // the candidate's own count is from when it was last seen, so estimate it again.
+            let hash = hash::fnv1a_64(&key);
+            let (h1, h2) = (hash as u32, (hash >> 32) as u32);
+            let count = sketch
+                .chunks_exact(width)
+                .enumerate()
+                .map(|(i, row)| {
+                    row[(h1.wrapping_add((i as u32).wrapping_mul(h2)) as usize) & (width - 1)]
+                })
+                .min()
+                .unwrap_or(0);
+            if count > 0 {
+                hot.push(HotKey {
+                    key,
+                    count: count as _,
+                });
+            }
+        }
+
+        hot.sort_by(|a, b| b.count.cmp(&a.count));
+        hot.truncate(k);
+        Ok(hot)
+    }
+
+    /// The ports that have sharding rules.
+    pub fn sharded_ports(&self) -> Vec<u16> {
+        self.rules.keys().copied().collect()
+    }
+
+    /// Sample 1 in `every` sharding decisions, across all rules, into a ring buffer that
+    /// [`sample::SampleReader`] reads. 0 stops sampling. Kernels before 5.8 have no ring
+    /// buffers, so there the samples go through a perf buffer per CPU instead. So do handles
+    /// loaded or opened with `XDP_SHARD_PERF_SAMPLES` set in the environment.
+    ///
+    /// Sampling is random, so this is 1 in `every` on average. Samples are dropped while the
+    /// buffer is full.
+    pub fn sample_decisions(&mut self, every: u32) -> Result<(), StdError> {
+        update_elem(self.sample_rate_map, "sample_rate_map", &0u32, &every)
+    }
+
+    pub(crate) fn sample_map_fd(&self) -> Result<std::os::raw::c_int, StdError> {
+        map_fd(self.sample_map, "sample_map")
+    }
+
+    /// Whether the samples go through perf buffers rather than a ring buffer.
+    pub(crate) fn samples_use_perf(&self) -> bool {
+        let def = unsafe { libbpf::bpf_map__def(self.sample_map) };
+        !def.is_null() && unsafe { (*def).type_ } == bpf::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY
+    }
+
+    fn key_field_sizes(&self, orig_port: u16) -> Result<Vec<usize>, StdError> {
+        let av = match self.rules.get(&orig_port) {
+            Some(av) => av,
+            None => Err(format!("Port is not sharded: {}", orig_port))?,
+        };
+
+        Ok(av.rules.fields[..av.rules.num_fields as usize]
+            .iter()
+            .map(|f| f.field_size as usize)
+            .collect())
+    }
+
+    // the XDP program copies each key field into its own slot.
+    fn override_key(&self, orig_port: u16, key: &[u8]) -> Result<OverrideKey, StdError> {
+        let sizes = self.key_field_sizes(orig_port)?;
+        if key.len() != sizes.iter().sum::<usize>() {
+            Err(format!(
+                "Key length does not match the fields of port {} ({:?}): {}",
+                orig_port,
+                sizes,
+                key.len()
+            ))?;
+        }
+
+        let mut okey = OverrideKey {
+            port: orig_port,
+            ..Default::default()
+        };
+        let mut rest = key;
+        for (slot, size) in okey.fields.iter_mut().zip(sizes) {
+            slot[..size].copy_from_slice(&rest[..size]);
+            rest = &rest[size..];
+        }
+
+        Ok(okey)
+    }
+
+    fn set_deliver(
+        &mut self,
+        orig_port: u16,
+        mut av: AvailableShards,
+        deliver: u32,
+    ) -> Result<(), StdError> {
+        av.deliver = deliver as _;
+        update_elem(
+            self.available_shards_map,
+            "available_shards_map",
+            &orig_port,
+            &av,
+        )?;
+
+        self.rules.insert(orig_port, av);
+        Ok(())
+    }
+
+    /// Number of shards of `orig_port`, if it is sharded.
+    pub(crate) fn num_shards(&self, orig_port: u16) -> Option<usize> {
+        self.rules.get(&orig_port).map(|av| av.num as usize)
+    }
+
+    /// Deliver shard `shard_idx`'s packets that arrive on `rxq` of the `iface`-th interface (in
+    /// [`interfaces`] order) to the AF_XDP socket `xsk_fd`.
+    pub(crate) fn set_xsk(
+        &mut self,
+        shard_idx: usize,
+        iface: usize,
+        rxq: u32,
+        xsk_fd: std::os::raw::c_int,
+    ) -> Result<(), StdError> {
+        if shard_idx >= xdp_shard::MAX_SHARDS as usize
+            || iface >= self.ifaces.len()
+            || rxq >= xdp_shard::MAX_RXQs
+        {
+            Err(format!(
+                "Invalid xsk slot: shard {}, interface {}, rxq {}",
+                shard_idx, iface, rxq
+            ))?;
+        }
+
+        let key = (shard_idx as u32 * xdp_shard::MAX_IFACES + iface as u32) * RXQ_SLOTS + rxq;
+        update_elem(self.xsks_map, "xsks_map", &key, &xsk_fd)
+    }
+
+    /// Switch `orig_port`'s shards between AF_XDP sockets and the kernel stack.
+    pub(crate) fn deliver_to_xsks(&mut self, orig_port: u16, on: bool) -> Result<(), StdError> {
+        let av = match self.rules.get(&orig_port) {
+            Some(av) => *av,
+            None => Err(format!("Port is not sharded: {}", orig_port))?,
+        };
+
+        let deliver = if on {
+            xdp_shard::DELIVER_XSK
+        } else {
+            xdp_shard::DELIVER_STACK
+        };
+        self.set_deliver(orig_port, av, deliver)
+    }
+
+    /// The interfaces the program is loaded on, and the mode each is attached in.
+    pub fn interfaces(&self) -> Vec<(u32, XdpMode)> {
+        self.ifaces
+            .iter()
+            .map(|i| (i.ifindex, i.xdp_mode))
+            .collect()
+    }
+
+    /// Rewrite the source port of replies from the shard ports back to the original port.
+    ///
+    /// Without this, servers on the shard ports reply from the shard port, which clients that
+    /// `connect()`ed to the original port will drop. This loads a TC egress program onto each
+    /// interface (with the `tc` command), which is removed again on drop. This fails on a
+    /// [`pin`]ned handle, since the egress program would not outlive it.
+    pub fn unshard_egress(&mut self) -> Result<(), StdError> {
+        if !self.egress.is_empty() {
+            return Ok(());
+        }
+
+        if self.pin_dir.is_some() {
+            Err(String::from(
+                "Cannot unshard egress on a pinned handle: it would stop on drop",
+            ))?;
+        }
+
+        let mut egress = Vec::with_capacity(self.ifaces.len());
+        for iface in self.ifaces.iter() {
+            let e = UnshardEgress::load(iface.ifindex)?;
+            for (&port, &(orig_port, _)) in self.egress_ports.iter() {
+                e.set(port, orig_port)?;
+            }
+
+            egress.push(e);
+        }
+
+        self.egress = egress;
+        Ok(())
+    }
+
+    /// Query cpu-rxq-port records of the first interface the program is loaded on.
+    ///
+    /// Returns (curr_record, prev_record) tuple. prev_record is equal to the previous call's
+    /// curr_record.
+    pub fn get_stats(&mut self) -> Result<(&StatsRecord, &StatsRecord), StdError> {
+        self.refresh_stats()?;
+        Ok((&self.curr_records[0], &self.prev_records[0]))
+    }
+
+    /// Like [`get_stats`], but for each interface the program is loaded on.
+    ///
+    /// Returns (ifindex, curr_record, prev_record) tuples, in [`interfaces`] order.
+    pub fn get_interface_stats(
+        &mut self,
+    ) -> Result<Vec<(u32, &StatsRecord, &StatsRecord)>, StdError> {
+        self.refresh_stats()?;
+        Ok(self
+            .ifaces
+            .iter()
+            .zip(self.curr_records.iter().zip(self.prev_records.iter()))
+            .map(|(iface, (curr, prev))| (iface.ifindex, curr, prev))
+            .collect())
+    }
+
+    fn refresh_stats(&mut self) -> Result<(), StdError> {
+        std::mem::swap(&mut self.prev_records, &mut self.curr_records);
+        for (i, curr) in self.curr_records.iter_mut().enumerate() {
+            *curr = StatsRecord::empty(i * self.num_rxqs, self.num_rxqs);
+            curr.update(self.rx_queue_index_map, self.port_stats_map)?;
+        }
+
+        Ok(())
+    }
+
+    /// Query the counts of aborted and dropped packets since the program was loaded.
+    ///
+    /// Vec<HashMap<DropReason, usize>> means: cpu_id -> reason -> count
+    pub fn get_drop_stats(&self) -> Result<Vec<HashMap<DropReason, usize>>, StdError> {
+        let fd = unsafe { libbpf::bpf_map__fd(self.drop_reason_map) };
+        if fd < 0 {
+            Err(format!("drop_reason_map returned bad fd: {}", fd))?;
+        }
+
+        let num_cpus = unsafe { libbpf::libbpf_num_possible_cpus() } as usize;
+        let mut cpus = vec![HashMap::new(); num_cpus];
+        let mut percpu_counts = vec![0u64; num_cpus];
+        for &reason in DropReason::ALL.iter() {
+            let key = reason.map_key();
+            let ok = unsafe {
+                bpf::bpf_map_lookup_elem(
+                    fd,
+                    &key as *const _ as *const _,
+                    percpu_counts.as_mut_ptr() as *mut _,
+                )
+            };
+            if ok != 0 {
+                Err(format!(
+                    "Could not bpf_map_lookup_elem for drop_reason_map: {:?}",
+                    reason
+                ))?;
+            }
+
+            for (cpu, count) in cpus.iter_mut().zip(percpu_counts.iter()) {
+                cpu.insert(reason, *count as usize);
+            }
+        }
+
+        Ok(cpus)
+    }
+
+    /// Query what happened to packets for `orig_port` since its shards were last set.
+    ///
+    /// Returns one [`ShardStats`] per cpu.
+    pub fn get_shard_stats(&self, orig_port: u16) -> Result<Vec<ShardStats>, StdError> {
+        let num_shards = match self.rules.get(&orig_port) {
+            Some(av) => av.num as usize,
+            None => Err(format!("Port is not sharded: {}", orig_port))?,
+        };
+
+        let fd = unsafe { libbpf::bpf_map__fd(self.shard_stats_map) };
+        if fd < 0 {
+            Err(format!("shard_stats_map returned bad fd: {}", fd))?;
+        }
+
+        let num_cpus = unsafe { libbpf::libbpf_num_possible_cpus() } as usize;
+        let mut percpu_stats = vec![ShardStatsRec::default(); num_cpus];
+        let ok = unsafe {
+            bpf::bpf_map_lookup_elem(
+                fd,
+                &orig_port as *const _ as *const _,
+                percpu_stats.as_mut_ptr() as *mut _,
+            )
+        };
+        if ok != 0 {
+            Err(format!(
+                "Could not bpf_map_lookup_elem for shard_stats_map: {}",
+                orig_port
+            ))?;
+        }
+
+        Ok(percpu_stats
+            .iter()
+            .map(|s| ShardStats {
+                shards: s.shards[..num_shards].iter().map(|&c| c as _).collect(),
+                disabled: s.unsharded[xdp_shard::UNSHARDED_DISABLED as usize] as _,
+                short_msg: s.unsharded[xdp_shard::UNSHARDED_SHORT_MSG as usize] as _,
+                bad_rule: s.unsharded[xdp_shard::UNSHARDED_BAD_RULE as usize] as _,
+                tcp_midflow: s.unsharded[xdp_shard::UNSHARDED_TCP_MIDFLOW as usize] as _,
+            })
+            .collect())
+    }
+
+    /// Run the loaded XDP program once on `pkt` with `BPF_PROG_TEST_RUN`.
+    ///
+    /// Returns the XDP action and the (possibly rewritten) packet. The kernel runs test packets
+    /// as if they arrived on the loopback device, so this is only useful on a handle loaded onto
+    /// `lo`.
+    pub fn test_run(&self, pkt: &[u8]) -> Result<(u32, Vec<u8>), StdError> {
+        prog_test_run(self.prog_fd, pkt)
+    }
+
+    /// Like [`test_run`], but run the TC egress program that [`unshard_egress`] loaded onto the
+    /// first interface.
+    ///
+    /// Returns the TC action and the (possibly rewritten) packet.
+    pub fn test_run_egress(&self, pkt: &[u8]) -> Result<(u32, Vec<u8>), StdError> {
+        match self.egress.first() {
+            Some(egress) => prog_test_run(egress.prog_fd, pkt),
+            None => Err(String::from("Egress unsharding is not on"))?,
+        }
+    }
+
+    // ifindex_map: ifindex -> position in self.ifaces.
+    fn set_ifindices(&mut self) -> Result<(), StdError> {
+        let ifindex_map = get_map_by_name("ifindex_map\0", self.bpf_obj)?;
+        for (i, iface) in self.ifaces.iter().enumerate() {
+            let ifindex = iface.ifindex as i32;
+            update_elem(ifindex_map, "ifindex_map", &ifindex, &(i as u32))?;
+        }
+
+        Ok(())
+    }
+
+    /// The mode the XDP program is attached in on the first interface it is loaded on. See
+    /// [`interfaces`] for the others.
+    pub fn xdp_mode(&self) -> XdpMode {
+        self.ifaces[0].xdp_mode
+    }
+
+    fn activate(&mut self, fallback_to_skb: bool) -> Result<(), StdError> {
+        for iface in self.ifaces.iter_mut() {
+            match attach_xdp(iface.ifindex, self.prog_fd, iface.xdp_mode) {
+                Err(e) if fallback_to_skb && iface.xdp_mode == XdpMode::Driver => {
+                    tracing::warn!(ifindex = iface.ifindex, err = ?e, "native xdp attach failed, falling back to skb mode");
+                    attach_xdp(iface.ifindex, self.prog_fd, XdpMode::Skb)?;
+                    iface.xdp_mode = XdpMode::Skb;
+                }
+                res => res?,
+            }
+
+            iface.attached = true;
+            tracing::info!(ifindex = iface.ifindex, mode = ?iface.xdp_mode, "attached xdp program");
+        }
+
+        Ok(())
+    }
+}
 
 impl Drop for BpfHandles {
     fn drop(&mut self) {
-        tracing::warn!("removing xdp program");
-        unsafe { remove_xdp(self.ifindex) }
+        if let Some(ref pin_dir) = self.pin_dir {
+            tracing::info!(?pin_dir, "leaving pinned xdp program attached");
+            return;
+        }
+
+        for iface in self.ifaces.iter().filter(|i| i.attached) {
+            tracing::warn!(ifindex = iface.ifindex, "removing xdp program");
+            unsafe { remove_xdp(iface.ifindex, iface.xdp_mode) }
+        }
+    }
+}
+
+// tc filter priority we attach the egress program at, so we can find it again to remove it.
+const UNSHARD_TC_PREF: &str = "4242";
+
+/// Handles for the TC egress program that undoes the port rewrite on replies.
+///
+/// On drop, detaches the program.
+#[derive(Debug)]
+struct UnshardEgress {
+    interface_name: String,
+    bpf_obj: *mut libbpf::bpf_object,
+    prog_fd: std::os::raw::c_int,
+    unshard_ports_map: *mut libbpf::bpf_map,
+}
+
+impl UnshardEgress {
+    fn load(interface_id: u32) -> Result<Self, StdError> {
+        let interface_name = get_interface_name(interface_id)?;
+        let bpf_filename = concat!(env!("OUT_DIR"), "/tc_unshard.o\0");
+        let (bpf_obj, prog_fd) =
+            load_bpf_obj(bpf_filename, libbpf::bpf_prog_type_BPF_PROG_TYPE_SCHED_CLS)?;
+        let unshard_ports_map = get_map_by_name("unshard_ports_map\0", bpf_obj)?;
+
+        // tc can only attach a program we loaded through a bpffs pin. It keeps its own reference,
+        // so the pin is only needed until then.
+        let pin_path = format!("/sys/fs/bpf/xdp_shard_tc_unshard_{}\0", interface_id);
+        let pin_path_cstr = std::ffi::CStr::from_bytes_with_nul(pin_path.as_bytes())?;
+        let ok = unsafe { bpf::bpf_obj_pin(prog_fd, pin_path_cstr.as_ptr()) };
+        if ok < 0 {
+            let errno = nix::errno::Errno::last();
+            unsafe { libbpf::bpf_object__close(bpf_obj) };
+            Err(format!("bpf_obj_pin {:?} failed: {}", pin_path_cstr, errno))?;
+        }
+
+        let pin_path = pin_path.trim_end_matches('\0');
+        let attached =
+            run_tc(&["qdisc", "replace", "dev", &interface_name, "clsact"]).and_then(|_| {
+                run_tc(&[
+                    "filter",
+                    "replace",
+                    "dev",
+                    &interface_name,
+                    "egress",
+                    "pref",
+                    UNSHARD_TC_PREF,
+                    "handle",
+                    "1",
+                    "bpf",
+                    "direct-action",
+                    "object-pinned",
+                    pin_path,
+                ])
+            });
+        std::fs::remove_file(pin_path)?;
+        if let Err(e) = attached {
+            unsafe { libbpf::bpf_object__close(bpf_obj) };
+            return Err(e);
+        }
+
+        Ok(UnshardEgress {
+            interface_name,
+            bpf_obj,
+            prog_fd,
+            unshard_ports_map,
+        })
+    }
+
+    /// Rewrite the source port of replies from port to orig_port.
+    fn set(&self, port: u16, orig_port: u16) -> Result<(), StdError> {
+        update_elem(
+            self.unshard_ports_map,
+            "unshard_ports_map",
+            &port,
+            &orig_port,
+        )
+    }
+
+    fn remove(&self, port: u16) -> Result<(), StdError> {
+        delete_elem(self.unshard_ports_map, "unshard_ports_map", &port)
+    }
+}
+
+impl Drop for UnshardEgress {
+    fn drop(&mut self) {
+        tracing::warn!("removing tc egress program");
+        if let Err(e) = run_tc(&[
+            "filter",
+            "del",
+            "dev",
+            &self.interface_name,
+            "egress",
+            "pref",
+            UNSHARD_TC_PREF,
+        ]) {
+            tracing::warn!(err = ?e, "failed to remove tc egress program");
+        }
+
+        unsafe { libbpf::bpf_object__close(self.bpf_obj) };
+    }
+}
+
+fn run_tc(args: &[&str]) -> Result<(), StdError> {
+    let out = std::process::Command::new("tc").args(args).output()?;
+    if !out.status.success() {
+        Err(format!(
+            "tc {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&out.stderr)
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Run the program `prog_fd` once on `pkt` with `BPF_PROG_TEST_RUN`.
+fn prog_test_run(prog_fd: std::os::raw::c_int, pkt: &[u8]) -> Result<(u32, Vec<u8>), StdError> {
+    let mut data = pkt.to_vec();
+    let mut data_out = vec![0u8; pkt.len() + 256];
+    let mut size_out = data_out.len() as u32;
+    let mut retval = 0u32;
+    let mut duration = 0u32;
+
+    let ok = unsafe {
+        bpf::bpf_prog_test_run(
+            prog_fd,
+            1,
+            data.as_mut_ptr() as *mut _,
+            data.len() as _,
+            data_out.as_mut_ptr() as *mut _,
+            &mut size_out as *mut _,
+            &mut retval as *mut _,
+            &mut duration as *mut _,
+        )
+    };
+    if ok < 0 {
+        let errno = nix::errno::Errno::last();
+        Err(format!("bpf_prog_test_run failed: {}", errno))?;
+    }
+
+    data_out.truncate(size_out as usize);
+    Ok((retval, data_out))
+}
+
+fn attach_xdp(
+    interface_id: u32,
+    prog_fd: std::os::raw::c_int,
+    mode: XdpMode,
+) -> Result<(), StdError> {
+    let xdp_flags = mode.flags() | if_link::XDP_FLAGS_UPDATE_IF_NOEXIST;
+    let ok = unsafe { libbpf::bpf_set_link_xdp_fd(interface_id as _, prog_fd, xdp_flags) };
+    if ok < 0 {
+        Err(format!("bpf_set_link_xdp_fd ({:?}) failed: {}", mode, ok))?;
     }
+
+    Ok(())
 }
 
-/// Remove any XDP program on the interface.
-pub unsafe fn remove_xdp(interface_id: u32) {
-    let xdp_flags = if_link::XDP_FLAGS_SKB_MODE | if_link::XDP_FLAGS_UPDATE_IF_NOEXIST;
+/// Remove any XDP program attached to the interface in `mode`.
+pub unsafe fn remove_xdp(interface_id: u32, mode: XdpMode) {
+    let xdp_flags = mode.flags() | if_link::XDP_FLAGS_UPDATE_IF_NOEXIST;
     libbpf::bpf_set_link_xdp_fd(interface_id as _, -1, xdp_flags);
 }
 
+/// (major, minor) of the running kernel.
+fn kernel_version() -> Option<(u32, u32)> {
+    let uts = nix::sys::utsname::uname();
+    let mut parts = uts.release().split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// The mode of the XDP program attached to the interface.
+fn get_xdp_mode(interface_id: u32) -> Result<XdpMode, StdError> {
+    let mut info: libbpf::xdp_link_info = unsafe { std::mem::zeroed() };
+    let ok = unsafe {
+        libbpf::bpf_get_link_xdp_info(
+            interface_id as _,
+            &mut info as *mut _,
+            std::mem::size_of_val(&info) as _,
+            0,
+        )
+    };
+    if ok < 0 {
+        Err(format!("bpf_get_link_xdp_info failed: {}", ok))?;
+    }
+
+    if info.drv_prog_id != 0 {
+        Ok(XdpMode::Driver)
+    } else if info.skb_prog_id != 0 {
+        Ok(XdpMode::Skb)
+    } else {
+        Err(format!(
+            "No xdp program attached to ifindex {}",
+            interface_id
+        ))?
+    }
+}
+
+// the XDP program to load: kernels before 5.8 have no ring buffers, so the program that samples
+// through perf buffers instead. XDP_SHARD_PERF_SAMPLES picks that one on any kernel, for tests.
+fn xdp_shard_obj() -> &'static str {
+    if ringbuf_supported() && std::env::var_os("XDP_SHARD_PERF_SAMPLES").is_none() {
+        concat!(env!("OUT_DIR"), "/xdp_shard.o\0")
+    } else {
+        concat!(env!("OUT_DIR"), "/xdp_shard_perf.o\0")
+    }
+}
+
+fn ringbuf_supported() -> bool {
+    let page_size = unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) };
+    let fd = unsafe {
+        bpf::bpf_create_map(
+            bpf::bpf_map_type_BPF_MAP_TYPE_RINGBUF,
+            0,
+            0,
+            page_size as _,
+            0,
+        )
+    };
+    if fd < 0 {
+        return false;
+    }
+
+    unsafe { nix::libc::close(fd) };
+    true
+}
+
+/// The interfaces in `bpf_obj`'s ifindex_map, ordered by their value.
+fn read_ifindices(bpf_obj: *mut libbpf::bpf_object) -> Result<Vec<u32>, StdError> {
+    let ifindex_map = get_map_by_name("ifindex_map\0", bpf_obj)?;
+    let fd = map_fd(ifindex_map, "ifindex_map")?;
+    let mut ifaces = vec![];
+    for key in map_keys::<i32>(ifindex_map, "ifindex_map")? {
+        let mut slot = 0u32;
+        let ok = unsafe {
+            bpf::bpf_map_lookup_elem(
+                fd,
+                &key as *const _ as *const _,
+                &mut slot as *mut _ as *mut _,
+            )
+        };
+        if ok != 0 {
+            Err(format!(
+                "Could not bpf_map_lookup_elem for ifindex_map: {}",
+                key
+            ))?;
+        }
+
+        ifaces.push((slot, key as u32));
+    }
+
+    ifaces.sort();
+    Ok(ifaces.into_iter().map(|(_, ifindex)| ifindex).collect())
+}
+
+/// `bpf_filename` must be nul-terminated.
+fn load_bpf_obj(
+    bpf_filename: &str,
+    prog_type: libbpf::bpf_prog_type,
+) -> Result<(*mut libbpf::bpf_object, std::os::raw::c_int), StdError> {
+    let bpf_filename_cstr = std::ffi::CStr::from_bytes_with_nul(bpf_filename.as_bytes())?;
+    let attr = libbpf::bpf_prog_load_attr {
+        file: bpf_filename_cstr.as_ptr(),
+        prog_type,
+        prog_flags: 0,
+        expected_attach_type: libbpf::bpf_attach_type_BPF_CGROUP_INET_INGRESS,
+        ifindex: 0,
+        log_level: 0,
+    };
+
+    let mut bpf_obj: *mut libbpf::bpf_object = std::ptr::null_mut();
+    let mut prog_fd = 0;
+
+    let ok = unsafe {
+        libbpf::bpf_prog_load_xattr(
+            &attr,
+            &mut bpf_obj as *mut *mut libbpf::bpf_object,
+            &mut prog_fd as *mut _,
+        )
+    };
+    if ok > 0 {
+        Err(format!("bpf_prog_load_xattr failed: {}", ok))?;
+    }
+
+    if prog_fd == 0 {
+        Err(format!("bpf_prog_load_xattr returned null fd"))?;
+    }
+
+    if prog_fd < 0 {
+        Err(format!("bpf_prog_load_xattr returned bad fd: {}", prog_fd))?;
+    }
+
+    Ok((bpf_obj, prog_fd))
+}
+
+fn obj_pin(fd: std::os::raw::c_int, path: &str) -> Result<(), StdError> {
+    let path_cstr = std::ffi::CString::new(path)?;
+    let ok = unsafe { bpf::bpf_obj_pin(fd, path_cstr.as_ptr()) };
+    if ok < 0 {
+        let errno = nix::errno::Errno::last();
+        Err(format!("bpf_obj_pin {} failed: {}", path, errno))?;
+    }
+
+    Ok(())
+}
+
+fn obj_get(path: &str) -> Result<std::os::raw::c_int, StdError> {
+    let path_cstr = std::ffi::CString::new(path)?;
+    let fd = unsafe { bpf::bpf_obj_get(path_cstr.as_ptr()) };
+    if fd < 0 {
+        let errno = nix::errno::Errno::last();
+        Err(format!("bpf_obj_get {} failed: {}", path, errno))?;
+    }
+
+    Ok(fd)
+}
+
 fn get_map_by_name(
     name: &str,
     bpf_obj: *mut libbpf::bpf_object,
@@ -339,6 +2109,102 @@ fn get_map_by_name(
     Ok(map)
 }
 
+fn map_fd(map: *mut libbpf::bpf_map, map_name: &str) -> Result<std::os::raw::c_int, StdError> {
+    let fd = unsafe { libbpf::bpf_map__fd(map) };
+    if fd < 0 {
+        Err(format!("{} returned bad fd: {}", map_name, fd))?;
+    }
+
+    Ok(fd)
+}
+
+// the keys in `map`. If the previous key is deleted (or evicted, for LRU maps) while we walk the
+// map, the kernel starts over from the first key, so the walk stops at the first repeated key.
+fn map_keys<K: Copy + Default>(
+    map: *mut libbpf::bpf_map,
+    map_name: &str,
+) -> Result<Vec<K>, StdError> {
+    let fd = map_fd(map, map_name)?;
+    let mut keys: Vec<K> = vec![];
+    let mut seen = std::collections::HashSet::new();
+    let mut key = K::default();
+    loop {
+        let prev_key_ptr = match keys.last() {
+            Some(k) => k as *const K,
+            None => std::ptr::null(),
+        };
+        let ok = unsafe {
+            bpf::bpf_map_get_next_key(fd, prev_key_ptr as *const _, &mut key as *mut _ as *mut _)
+        };
+        if ok != 0 {
+            // ENOENT: no more keys
+            break;
+        }
+
+        let bytes = unsafe {
+            std::slice::from_raw_parts(&key as *const K as *const u8, std::mem::size_of::<K>())
+        };
+        if !seen.insert(bytes.to_vec()) {
+            break;
+        }
+
+        keys.push(key);
+    }
+
+    Ok(keys)
+}
+
+fn update_elem<K, V: ?Sized>(
+    map: *mut libbpf::bpf_map,
+    map_name: &str,
+    key: &K,
+    val: &V,
+) -> Result<(), StdError> {
+    let fd = map_fd(map, map_name)?;
+    let ok = unsafe {
+        bpf::bpf_map_update_elem(
+            fd,
+            key as *const K as *const _,
+            val as *const V as *const u8 as *const _,
+            0,
+        )
+    };
+    if ok < 0 {
+        let errno = nix::errno::Errno::last();
+        Err(format!("{} update elem failed: {}", map_name, errno))?;
+    }
+
+    Ok(())
+}
+
+fn delete_elem<K>(map: *mut libbpf::bpf_map, map_name: &str, key: &K) -> Result<(), StdError> {
+    let fd = map_fd(map, map_name)?;
+    let ok = unsafe { bpf::bpf_map_delete_elem(fd, key as *const K as *const _) };
+    if ok < 0 {
+        let errno = nix::errno::Errno::last();
+        if errno != nix::errno::Errno::ENOENT {
+            Err(format!("{} delete elem failed: {}", map_name, errno))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn get_interface_name(interface_id: u32) -> Result<String, StdError> {
+    let mut buf = [0u8; nix::libc::IF_NAMESIZE];
+    let name = unsafe { nix::libc::if_indextoname(interface_id, buf.as_mut_ptr() as *mut _) };
+    if name.is_null() {
+        Err(format!(
+            "if_indextoname({}) failed: {}",
+            interface_id,
+            nix::errno::Errno::last()
+        ))?;
+    }
+
+    let name = unsafe { std::ffi::CStr::from_ptr(name) };
+    Ok(name.to_str()?.to_owned())
+}
+
 fn get_interface_id(interface_name: &str) -> Result<u32, StdError> {
     Ok(nix::net::if_::if_nametoindex(interface_name)?)
 }
@@ -0,0 +1,71 @@
+//! Maglev consistent hashing lookup tables (Eisenbud et al., NSDI '16).
+//!
+//! The XDP program picks a shard with `table[hash % table.len()]`. Each shard port gets its own
+//! pseudo-random permutation of the table slots, derived only from the port number, and the
+//! shards take turns claiming their next free preferred slot. Adding or removing one shard then
+//! only moves about 1/N of the slots.
+//!
+//! Weighted shards get turns in proportion to their weight, so they end up owning that share
+//! of the table.
+
+const FNV1A_64_INIT: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_64_PRIME: u64 = 0x100_0000_01b3;
+
+fn fnv1a_64(salt: u8, port: u16) -> u64 {
+    let mut hash = FNV1A_64_INIT;
+    for b in std::iter::once(salt).chain(port.to_be_bytes().iter().copied()) {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(FNV_64_PRIME);
+    }
+
+    hash
+}
+
+/// Build a lookup table of `size` slots, each holding an index into `ports`.
+///
+/// `ports` is a list of `(port, weight)`. `size` should be prime and much larger than
+/// `ports.len()`.
+pub fn populate(ports: &[(u16, u32)], size: usize) -> Vec<u16> {
+    assert!(ports.iter().any(|&(_, w)| w > 0));
+    assert!(size > 1);
+    let size = size as u64;
+    let max_weight = ports.iter().map(|&(_, w)| w as u64).max().unwrap();
+
+    let perms: Vec<(u64, u64)> = ports
+        .iter()
+        .map(|&(p, _)| {
+            let offset = fnv1a_64(0, p) % size;
+            let skip = fnv1a_64(1, p) % (size - 1) + 1;
+            (offset, skip)
+        })
+        .collect();
+
+    let mut next = vec![0u64; ports.len()];
+    let mut credit = vec![0u64; ports.len()];
+    let mut table = vec![u16::MAX; size as usize];
+    let mut filled = 0;
+    loop {
+        for (i, (offset, skip)) in perms.iter().enumerate() {
+            // the heaviest shard claims a slot every round, the others only once they have
+            // saved up enough weight.
+            credit[i] += ports[i].1 as u64;
+            if credit[i] < max_weight {
+                continue;
+            }
+
+            credit[i] -= max_weight;
+            let mut slot = (offset + next[i] * skip) % size;
+            while table[slot as usize] != u16::MAX {
+                next[i] += 1;
+                slot = (offset + next[i] * skip) % size;
+            }
+
+            table[slot as usize] = i as u16;
+            next[i] += 1;
+            filled += 1;
+            if filled == size {
+                return table;
+            }
+        }
+    }
+}
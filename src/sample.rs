@@ -0,0 +1,417 @@
+//! Sampled sharding decisions: with [`BpfHandles::sample_decisions`] on, the XDP program writes
+//! 1 in N decisions to its `sample_map`, and [`SampleReader`] reads them.
+//!
+//! `sample_map` is a BPF ring buffer, mapped into this process: a consumer position page that we
+//! write, then the producer position page and the data pages, which are read-only. The data
+//! pages are mapped twice in a row, so a record that wraps around the end can still be read in
+//! one piece.
+//!
+//! Kernels before 5.8 have no ring buffers, so there `sample_map` is a perf event array, and each
+//! CPU gets a perf buffer: a header page with the head and tail positions, then the data pages.
+//! These are mapped only once, so records that wrap around are copied out in two pieces.
+
+use crate::bindings::bpf;
+use crate::bindings::libbpf;
+use crate::bindings::xdp_shard::{self, ShardSampleRec};
+use crate::xsk::{Fd, Mmap};
+use crate::{BpfHandles, StdError, RXQ_SLOTS};
+use nix::libc;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+// record header: u32 length and flags, then u32 page offset.
+const HDR_SIZE: usize = 8;
+const BUSY_BIT: u32 = 1 << 31;
+const DISCARD_BIT: u32 = 1 << 30;
+const POLL_TIMEOUT_MS: c_int = 100;
+
+// perf_event_open(2), for the perf buffers.
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_BPF_OUTPUT: u64 = 10;
+const PERF_SAMPLE_RAW: u64 = 1 << 10;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+const PERF_RECORD_SAMPLE: u32 = 9;
+// struct perf_event_mmap_page offsets
+const PERF_DATA_HEAD: usize = 1024;
+const PERF_DATA_TAIL: usize = 1032;
+// struct perf_event_header, then the u32 size of the raw sample.
+const PERF_HDR_SIZE: usize = 8;
+const PERF_RAW_HDR_SIZE: usize = PERF_HDR_SIZE + 4;
+
+// struct perf_event_attr, as of its first version (PERF_ATTR_SIZE_VER0).
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// A sharding decision made by the XDP program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShardSample {
+    pub src_addr: IpAddr,
+    pub orig_port: u16,
+    /// The shard key, its fields concatenated. Empty for TCP, which shards on the 4-tuple.
+    pub key: Vec<u8>,
+    /// The hash of the key, or of the 4-tuple for TCP. 0 for pinned keys.
+    pub hash: u64,
+    /// The shard index, or `None` if the key is pinned with [`BpfHandles::pin_key`].
+    pub shard: Option<usize>,
+    /// The port the packet was rewritten to.
+    pub port: u16,
+    pub tcp: bool,
+    pub ifindex: u32,
+    /// The rx queue. Queues >= `MAX_RXQs` all show as `MAX_RXQs`.
+    pub rxq: u32,
+    pub cpu: u32,
+}
+
+/// Reads sampled sharding decisions. See [`BpfHandles::sample_decisions`].
+#[derive(Debug)]
+pub struct SampleReader {
+    source: Source,
+    ifindices: Vec<u32>,
+}
+
+#[derive(Debug)]
+enum Source {
+    Ring(RingBuf),
+    Perf(Vec<PerfBuf>),
+}
+
+impl SampleReader {
+    /// Map `handles`' sample buffers. The reader stays valid after `handles` is dropped.
+    ///
+    /// There should be only one reader at a time: readers share the consumer position, and
+    /// with perf buffers a new reader replaces the old one's buffers.
+    pub fn new(handles: &BpfHandles) -> Result<Self, StdError> {
+        let map_fd = handles.sample_map_fd()?;
+        let source = if handles.samples_use_perf() {
+            let num_cpus = unsafe { libbpf::libbpf_num_possible_cpus() } as u32;
+            let mut bufs = vec![];
+            for cpu in 0..num_cpus.min(xdp_shard::MAX_CPUS) {
+                if let Some(buf) = PerfBuf::new(map_fd, cpu)? {
+                    bufs.push(buf);
+                }
+            }
+
+            Source::Perf(bufs)
+        } else {
+            Source::Ring(RingBuf::new(map_fd)?)
+        };
+
+        Ok(SampleReader {
+            source,
+            ifindices: handles.interfaces().iter().map(|&(i, _)| i).collect(),
+        })
+    }
+
+    /// Call `f` on each sample written so far, without blocking. Returns how many there were.
+    pub fn poll(&mut self, mut f: impl FnMut(ShardSample)) -> usize {
+        let ifindices = &self.ifindices;
+        let mut each = |rec: &ShardSampleRec| f(decode(ifindices, rec));
+        match self.source {
+            Source::Ring(ref ring) => ring.poll(&mut each),
+            Source::Perf(ref bufs) => bufs.iter().map(|b| b.poll(&mut each)).sum(),
+        }
+    }
+
+    /// Call `f` on samples as they arrive, until `stop` is set.
+    pub fn run(
+        &mut self,
+        stop: &AtomicBool,
+        mut f: impl FnMut(ShardSample),
+    ) -> Result<(), StdError> {
+        let fds = match self.source {
+            Source::Ring(ref ring) => vec![ring.fd.0],
+            Source::Perf(ref bufs) => bufs.iter().map(|b| b.fd.0).collect(),
+        };
+        let mut pollfds: Vec<_> = fds
+            .into_iter()
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        while !stop.load(Ordering::SeqCst) {
+            let ok =
+                unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as _, POLL_TIMEOUT_MS) };
+            if ok < 0 {
+                let errno = nix::errno::Errno::last();
+                if errno == nix::errno::Errno::EINTR {
+                    continue;
+                }
+
+                Err(format!("poll on sample_map failed: {}", errno))?;
+            }
+
+            self.poll(&mut f);
+        }
+
+        Ok(())
+    }
+}
+
+fn decode(ifindices: &[u32], rec: &ShardSampleRec) -> ShardSample {
+    let src_addr = if rec.flags as u32 & xdp_shard::SAMPLE_IPV6 != 0 {
+        let mut octets = [0u8; 16];
+        for (o, w) in octets.chunks_mut(4).zip(rec.saddr.iter()) {
+            o.copy_from_slice(&w.to_ne_bytes());
+        }
+
+        IpAddr::V6(Ipv6Addr::from(octets))
+    } else {
+        IpAddr::V4(Ipv4Addr::from(rec.saddr[0].to_ne_bytes()))
+    };
+
+    let key = rec
+        .field_sizes
+        .iter()
+        .zip(rec.key.iter())
+        .flat_map(|(&size, field)| field[..size as usize].iter().copied())
+        .collect();
+
+    let pinned = rec.flags as u32 & xdp_shard::SAMPLE_PINNED != 0;
+    let iface = (rec.rxq / RXQ_SLOTS) as usize;
+    ShardSample {
+        src_addr,
+        orig_port: rec.orig_port,
+        key,
+        hash: rec.hash,
+        shard: if pinned { None } else { Some(rec.idx as usize) },
+        port: rec.port,
+        tcp: rec.flags as u32 & xdp_shard::SAMPLE_TCP != 0,
+        ifindex: ifindices.get(iface).copied().unwrap_or(0),
+        rxq: rec.rxq % RXQ_SLOTS,
+        cpu: rec.cpu,
+    }
+}
+
+// the BPF ring buffer.
+#[derive(Debug)]
+struct RingBuf {
+    fd: Fd,
+    _consumer: Mmap,
+    _data: Mmap,
+    consumer_pos: *const AtomicUsize,
+    producer_pos: *const AtomicUsize,
+    data: *const u8,
+    mask: usize,
+}
+
+impl RingBuf {
+    fn new(map_fd: c_int) -> Result<Self, StdError> {
+        let fd = unsafe { libc::dup(map_fd) };
+        if fd < 0 {
+            let errno = nix::errno::Errno::last();
+            Err(format!("dup sample_map fd failed: {}", errno))?;
+        }
+        let fd = Fd(fd);
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = xdp_shard::SAMPLE_RINGBUF_SIZE as usize;
+        let consumer = Mmap::new(
+            page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.0,
+            0,
+            "sample_map consumer page",
+        )?;
+        let data = Mmap::new(
+            page_size + 2 * size,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            fd.0,
+            page_size as _,
+            "sample_map data pages",
+        )?;
+
+        Ok(RingBuf {
+            fd,
+            consumer_pos: consumer.ptr as *const AtomicUsize,
+            producer_pos: data.ptr as *const AtomicUsize,
+            data: unsafe { (data.ptr as *const u8).add(page_size) },
+            _consumer: consumer,
+            _data: data,
+            mask: size - 1,
+        })
+    }
+
+    fn poll(&self, f: &mut impl FnMut(&ShardSampleRec)) -> usize {
+        let consumer_pos = unsafe { &*self.consumer_pos };
+        let producer_pos = unsafe { &*self.producer_pos };
+
+        let mut num = 0;
+        let mut cons = consumer_pos.load(Ordering::Acquire);
+        loop {
+            let prod = producer_pos.load(Ordering::Acquire);
+            if cons >= prod {
+                break;
+            }
+
+            while cons < prod {
+                let hdr = unsafe { self.data.add(cons & self.mask) };
+                let len = unsafe { &*(hdr as *const AtomicU32) }.load(Ordering::Acquire);
+                if len & BUSY_BIT != 0 {
+                    // still being written.
+                    consumer_pos.store(cons, Ordering::Release);
+                    return num;
+                }
+
+                let rec_len = (len & !(BUSY_BIT | DISCARD_BIT)) as usize;
+                if len & DISCARD_BIT == 0 && rec_len >= std::mem::size_of::<ShardSampleRec>() {
+                    let rec = unsafe {
+                        std::ptr::read_unaligned(hdr.add(HDR_SIZE) as *const ShardSampleRec)
+                    };
+                    f(&rec);
+                    num += 1;
+                }
+
+                cons += (rec_len + HDR_SIZE + 7) & !7;
+                consumer_pos.store(cons, Ordering::Release);
+            }
+        }
+
+        num
+    }
+}
+
+// one CPU's perf buffer.
+#[derive(Debug)]
+struct PerfBuf {
+    fd: Fd,
+    mmap: Mmap,
+    data: *const u8,
+    size: usize,
+}
+
+impl PerfBuf {
+    // None if the CPU is offline.
+    fn new(map_fd: c_int, cpu: u32) -> Result<Option<Self>, StdError> {
+        let attr = PerfEventAttr {
+            type_: PERF_TYPE_SOFTWARE,
+            size: std::mem::size_of::<PerfEventAttr>() as _,
+            config: PERF_COUNT_SW_BPF_OUTPUT,
+            sample_period: 1,
+            sample_type: PERF_SAMPLE_RAW,
+            wakeup_events: 1,
+            ..Default::default()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                -1 as libc::pid_t,
+                cpu as c_int,
+                -1 as c_int,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            let errno = nix::errno::Errno::last();
+            if errno == nix::errno::Errno::ENODEV {
+                return Ok(None);
+            }
+
+            Err(format!("perf_event_open on cpu {} failed: {}", cpu, errno))?;
+        }
+        let fd = Fd(fd as _);
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = xdp_shard::SAMPLE_PERF_PAGES as usize * page_size;
+        let mmap = Mmap::new(
+            page_size + size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.0,
+            0,
+            "sample perf buffer",
+        )?;
+
+        let ok = unsafe {
+            bpf::bpf_map_update_elem(
+                map_fd,
+                &cpu as *const _ as *const _,
+                &fd.0 as *const _ as *const _,
+                0,
+            )
+        };
+        if ok < 0 {
+            let errno = nix::errno::Errno::last();
+            Err(format!("sample_map update elem failed: {}", errno))?;
+        }
+
+        Ok(Some(PerfBuf {
+            fd,
+            data: unsafe { (mmap.ptr as *const u8).add(page_size) },
+            mmap,
+            size,
+        }))
+    }
+
+    fn poll(&self, f: &mut impl FnMut(&ShardSampleRec)) -> usize {
+        let page = self.mmap.ptr as *const u8;
+        let head = unsafe { &*(page.add(PERF_DATA_HEAD) as *const AtomicU64) };
+        let tail = unsafe { &*(page.add(PERF_DATA_TAIL) as *const AtomicU64) };
+
+        let mut num = 0;
+        let mut pos = tail.load(Ordering::Relaxed) as usize;
+        let end = head.load(Ordering::Acquire) as usize;
+        while pos < end {
+            // records are 8-byte aligned, so the header doesn't wrap.
+            let hdr = unsafe { self.data.add(pos % self.size) };
+            let type_ = unsafe { std::ptr::read_unaligned(hdr as *const u32) };
+            let len = unsafe { std::ptr::read_unaligned(hdr.add(6) as *const u16) } as usize;
+            if len < PERF_HDR_SIZE {
+                break;
+            }
+
+            // anything else is a count of lost samples.
+            if type_ == PERF_RECORD_SAMPLE && len >= PERF_RAW_HDR_SIZE {
+                let rec = self.copy_out(pos, len);
+                let raw_len = u32::from_ne_bytes([rec[8], rec[9], rec[10], rec[11]]) as usize;
+                if raw_len >= std::mem::size_of::<ShardSampleRec>()
+                    && rec.len() >= PERF_RAW_HDR_SIZE + std::mem::size_of::<ShardSampleRec>()
+                {
+                    let rec = unsafe {
+                        std::ptr::read_unaligned(
+                            rec[PERF_RAW_HDR_SIZE..].as_ptr() as *const ShardSampleRec
+                        )
+                    };
+                    f(&rec);
+                    num += 1;
+                }
+            }
+
+            pos += len;
+        }
+
+        tail.store(pos as u64, Ordering::Release);
+        num
+    }
+
+    // the len bytes at pos, which may wrap around the end of the buffer.
+    fn copy_out(&self, pos: usize, len: usize) -> Vec<u8> {
+        let start = pos % self.size;
+        let first = len.min(self.size - start);
+        let mut rec = Vec::with_capacity(len);
+        unsafe {
+            rec.extend_from_slice(std::slice::from_raw_parts(self.data.add(start), first));
+            rec.extend_from_slice(std::slice::from_raw_parts(self.data, len - first));
+        }
+
+        rec
+    }
+}
@@ -0,0 +1,147 @@
+#include <asm/byteorder.h>
+#include <linux/stddef.h>
+#include <linux/if_ether.h>
+#include <linux/ip.h>
+#include <linux/ipv6.h>
+#include <linux/in.h>
+#include <linux/tcp.h>
+#include <linux/udp.h>
+#include <linux/bpf.h>
+#include <linux/pkt_cls.h>
+#include <linux/types.h>
+#include "bpf_helpers.h"
+#include "xdp_shard.h"
+
+/*
+ * TC egress companion to xdp_shard.c.
+ *
+ * xdp_shard rewrites the destination port of incoming packets from orig_port to one of the
+ * shard ports, so the servers reply from the shard port. This rewrites the source port of
+ * those replies back to orig_port, so that sharding is invisible to (e.g. connected) clients.
+ */
+
+typedef __u8 u8;
+typedef __u16 u16;
+typedef __u32 u32;
+typedef __u64 u64;
+
+#define ntohs(x) __constant_ntohs(x)
+#define htons(x) __constant_htons(x)
+
+/* Shard port -> orig_port, both host order. Filled from userspace along with the shard rules and
+ * pinned keys. A shard port belongs to one rule, so there are at most MAX_SHARDS for each of
+ * MAX_RULES, plus one for each pinned key.
+ */
+struct bpf_map_def SEC("maps") unshard_ports_map = {
+	.type		= BPF_MAP_TYPE_HASH,
+	.key_size	= sizeof(__u16),
+	.value_size	= sizeof(__u16),
+	.max_entries	= MAX_RULES * MAX_SHARDS + MAX_OVERRIDES,
+};
+
+struct vlan_hdr {
+    u16 h_vlan_TCI;
+    u16 h_vlan_encapsulated_proto;
+};
+
+#define MAX_VLAN_TAGS 2
+#define IPV4_FRAG_OFFSET_MASK 0x1fff
+
+/* Rewrite the 16-bit source port at port_off, fixing up the checksum at csum_off.
+ * bpf_l4_csum_replace does the right thing for checksum offload (CHECKSUM_PARTIAL).
+ */
+static inline int unshard_port(struct __sk_buff *skb, u16 sport, u32 port_off, u32 csum_off, u64 csum_flags)
+{
+    u16 *orig_port;
+    u16 le_port = ntohs(sport);
+    u16 new_port;
+
+    orig_port = bpf_map_lookup_elem(&unshard_ports_map, &le_port);
+    if (!orig_port) {
+        return TC_ACT_OK;
+    }
+
+    new_port = htons(*orig_port);
+    bpf_l4_csum_replace(skb, csum_off, sport, new_port, csum_flags | sizeof(new_port));
+    bpf_skb_store_bytes(skb, port_off, &new_port, sizeof(new_port), 0);
+    return TC_ACT_OK;
+}
+
+static inline int parse_l4(struct __sk_buff *skb, u8 proto, u32 l4_off)
+{
+    void *data_end = (void *)(long)skb->data_end;
+    void *data = (void *)(long)skb->data;
+    struct udphdr *uh;
+    struct tcphdr *th;
+
+    if (proto == IPPROTO_UDP) {
+        uh = data + l4_off;
+        if ((void*) (uh + 1) > data_end)
+            return TC_ACT_OK;
+        return unshard_port(skb, uh->source,
+            l4_off + __builtin_offsetof(struct udphdr, source),
+            l4_off + __builtin_offsetof(struct udphdr, check),
+            BPF_F_MARK_MANGLED_0);
+    } else if (proto == IPPROTO_TCP) {
+        th = data + l4_off;
+        if ((void*) (th + 1) > data_end)
+            return TC_ACT_OK;
+        return unshard_port(skb, th->source,
+            l4_off + __builtin_offsetof(struct tcphdr, source),
+            l4_off + __builtin_offsetof(struct tcphdr, check),
+            0);
+    }
+
+    return TC_ACT_OK;
+}
+
+SEC("tc_unshard")
+int tc_unshard_prog(struct __sk_buff *skb)
+{
+    void *data_end = (void *)(long)skb->data_end;
+    void *data = (void *)(long)skb->data;
+    struct ethhdr *eth = data;
+    struct vlan_hdr *vlh;
+    struct iphdr *iph;
+    struct ipv6hdr *ip6h;
+    u32 nh_off;
+    u16 h_proto;
+    u8 i;
+
+    nh_off = sizeof(*eth);
+    if (data + nh_off > data_end)
+        return TC_ACT_OK;
+
+    h_proto = eth->h_proto;
+    #pragma clang loop unroll(full)
+    for (i = 0; i < MAX_VLAN_TAGS; i++) {
+        if (h_proto != htons(ETH_P_8021Q) && h_proto != htons(ETH_P_8021AD)) {
+            break;
+        }
+
+        vlh = data + nh_off;
+        if ((void*) (vlh + 1) > data_end)
+            return TC_ACT_OK;
+        h_proto = vlh->h_vlan_encapsulated_proto;
+        nh_off += sizeof(*vlh);
+    }
+
+    if (h_proto == htons(ETH_P_IP)) {
+        iph = data + nh_off;
+        if ((void*) (iph + 1) > data_end)
+            return TC_ACT_OK;
+        if (iph->ihl < 5 || (ntohs(iph->frag_off) & IPV4_FRAG_OFFSET_MASK))
+            return TC_ACT_OK;
+        return parse_l4(skb, iph->protocol, nh_off + iph->ihl * 4);
+    } else if (h_proto == htons(ETH_P_IPV6)) {
+        // locally generated replies don't carry extension headers, so don't walk them.
+        ip6h = data + nh_off;
+        if ((void*) (ip6h + 1) > data_end)
+            return TC_ACT_OK;
+        return parse_l4(skb, ip6h->nexthdr, nh_off + sizeof(*ip6h));
+    }
+
+    return TC_ACT_OK;
+}
+
+char _license[] SEC("license") = "GPL";
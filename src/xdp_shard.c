@@ -12,14 +12,7 @@
 #include <linux/types.h>
 #include "bpf_helpers.h"
 #include "xdp_shard.h"
-
-typedef __u64 Fnv64_t;
-
-/*
- * 64 bit magic FNV-0 and FNV-1 prime
- */
-#define FNV1_64_INIT ((Fnv64_t)0xcbf29ce484222325ULL)
-#define FNV_64_PRIME ((Fnv64_t)0x100000001b3ULL)
+#include "hash.h"
 
 typedef __u8 u8;
 typedef __u16 u16;
@@ -29,22 +22,30 @@ typedef __u64 u64;
 #define ntohs(x) __constant_ntohs(x)
 #define htons(x) __constant_htons(x)
 
-// set from userspace with the ifindex
+// ifindex -> iface, 0..MAX_IFACES. set from userspace with the interfaces we are attached to.
 struct bpf_map_def SEC("maps") ifindex_map = {
-	.type		= BPF_MAP_TYPE_ARRAY,
+	.type		= BPF_MAP_TYPE_HASH,
 	.key_size	= sizeof(int),
-	.value_size	= sizeof(int),
-	.max_entries	= 1,
+	.value_size	= sizeof(__u32),
+	.max_entries	= MAX_IFACES,
 };
 
-#define MAX_RXQs 64
-
-/* Stats per port, per rx_queue_index, per CPU */
+/* IP header stats per rxq slot, per CPU */
 struct bpf_map_def SEC("maps") rx_queue_index_map = {
 	.type		= BPF_MAP_TYPE_PERCPU_ARRAY,
 	.key_size	= sizeof(__u32),
 	.value_size	= sizeof(struct datarec),
-	.max_entries	= MAX_RXQs + 1,
+	.max_entries	= MAX_IFACES * RXQ_SLOTS,
+};
+
+/* Stats per (rxq slot, port, proto), per CPU. Ports that go quiet get evicted, so
+ * ephemeral-port traffic can't crowd out the ones we care about.
+ */
+struct bpf_map_def SEC("maps") port_stats_map = {
+	.type		= BPF_MAP_TYPE_LRU_PERCPU_HASH,
+	.key_size	= sizeof(struct port_key),
+	.value_size	= sizeof(struct port_rec),
+	.max_entries	= 16384,
 };
 
 /* Dest. port -> available_shards map for sharding on that port */
@@ -52,198 +53,885 @@ struct bpf_map_def SEC("maps") available_shards_map = {
 	.type		= BPF_MAP_TYPE_HASH,
 	.key_size	= sizeof(__u16),
 	.value_size	= sizeof(struct available_shards),
-	.max_entries	= 4,
+	.max_entries	= MAX_RULES,
 };
 
-static inline int shard_generic(void *app_data, void *data_end, u16 *port) {
-    struct available_shards *shards;
-    u16 le_port = ntohs(*port);
-    u16 out_port;
+/* Dest. port -> maglev lookup table, filled from userspace, for SHARD_MODE_MAGLEV */
+struct bpf_map_def SEC("maps") maglev_table_map = {
+	.type		= BPF_MAP_TYPE_HASH,
+	.key_size	= sizeof(__u16),
+	.value_size	= sizeof(struct maglev_table),
+	.max_entries	= MAX_RULES,
+	.map_flags	= BPF_F_NO_PREALLOC, // tables are big, only allocate the ones in use
+};
+
+/* (orig_port, shard key) -> port for hot keys, checked before hashing. */
+struct bpf_map_def SEC("maps") key_override_map = {
+	.type		= BPF_MAP_TYPE_HASH,
+	.key_size	= sizeof(struct override_key),
+	.value_size	= sizeof(struct override_rec),
+	.max_entries	= MAX_OVERRIDES,
+};
+
+/* orig_port (host order) -> count-min sketch of its shard keys */
+struct bpf_map_def SEC("maps") key_sketch_map = {
+	.type		= BPF_MAP_TYPE_HASH,
+	.key_size	= sizeof(__u16),
+	.value_size	= sizeof(struct key_sketch),
+	.max_entries	= MAX_RULES,
+	.map_flags	= BPF_F_NO_PREALLOC,
+};
+
+/* (orig_port, shard key) -> its sketch estimate when last seen. Hot keys are seen often, so they
+ * stay in the LRU.
+ */
+struct bpf_map_def SEC("maps") hot_key_map = {
+	.type		= BPF_MAP_TYPE_LRU_HASH,
+	.key_size	= sizeof(struct override_key),
+	.value_size	= sizeof(__u64),
+	.max_entries	= MAX_HOT_KEYS,
+};
+
+/* [0]: sample 1 in this many sharding decisions. 0 turns sampling off. */
+struct bpf_map_def SEC("maps") sample_rate_map = {
+	.type		= BPF_MAP_TYPE_ARRAY,
+	.key_size	= sizeof(__u32),
+	.value_size	= sizeof(__u32),
+	.max_entries	= 1,
+};
+
+#ifdef SAMPLE_PERF
+/* cpu -> perf event fd, for struct shard_sample records */
+struct bpf_map_def SEC("maps") sample_map = {
+	.type		= BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+	.key_size	= sizeof(__u32),
+	.value_size	= sizeof(__u32),
+	.max_entries	= MAX_CPUS,
+};
+
+/* perf output needs the xdp ctx, so the sample waits here until xdp_steer sends it. */
+struct sample_scratch {
+    struct shard_sample sample;
+    __u32 pending;
+};
+
+struct bpf_map_def SEC("maps") sample_scratch_map = {
+	.type		= BPF_MAP_TYPE_PERCPU_ARRAY,
+	.key_size	= sizeof(__u32),
+	.value_size	= sizeof(struct sample_scratch),
+	.max_entries	= 1,
+};
+#else
+/* struct shard_sample records */
+struct bpf_map_def SEC("maps") sample_map = {
+	.type		= BPF_MAP_TYPE_RINGBUF,
+	.max_entries	= SAMPLE_RINGBUF_SIZE,
+};
+#endif
+
+/* orig_port (host order) -> split points, for SHARD_MODE_RANGE rules */
+struct bpf_map_def SEC("maps") range_splits_map = {
+	.type		= BPF_MAP_TYPE_HASH,
+	.key_size	= sizeof(__u16),
+	.value_size	= sizeof(struct range_splits),
+	.max_entries	= MAX_RULES,
+};
+
+/* cpu -> queue size, filled from userspace for the cpus that shards are redirected to */
+struct bpf_map_def SEC("maps") cpu_map = {
+	.type		= BPF_MAP_TYPE_CPUMAP,
+	.key_size	= sizeof(__u32),
+	.value_size	= sizeof(__u32),
+	.max_entries	= MAX_CPUS,
+};
+
+/* (shard index * MAX_IFACES * RXQ_SLOTS + rxq slot) -> AF_XDP socket, filled from userspace for
+ * DELIVER_XSK. An AF_XDP socket only receives from the interface and rx queue it is bound to, so
+ * each shard needs one socket per rx queue of each interface.
+ */
+struct bpf_map_def SEC("maps") xsks_map = {
+	.type		= BPF_MAP_TYPE_XSKMAP,
+	.key_size	= sizeof(__u32),
+	.value_size	= sizeof(__u32),
+	.max_entries	= MAX_SHARDS * MAX_IFACES * RXQ_SLOTS,
+};
+
+/* Per-cpu count of aborted or dropped packets, by DROP_* reason */
+struct bpf_map_def SEC("maps") drop_reason_map = {
+	.type		= BPF_MAP_TYPE_PERCPU_ARRAY,
+	.key_size	= sizeof(__u32),
+	.value_size	= sizeof(__u64),
+	.max_entries	= NUM_DROP_REASONS,
+};
+
+/* Count a packet that is aborted or dropped for reason, and return action. */
+static inline int drop(u32 reason, int action)
+{
+    u64 *count;
+
+    count = bpf_map_lookup_elem(&drop_reason_map, &reason);
+    if (count) {
+        (*count)++;
+    }
+
+    return action;
+}
+
+/* Dest. port -> per-cpu shard_stats for that port's rule. Created from userspace with the rule. */
+struct bpf_map_def SEC("maps") shard_stats_map = {
+	.type		= BPF_MAP_TYPE_PERCPU_HASH,
+	.key_size	= sizeof(__u16),
+	.value_size	= sizeof(struct shard_stats),
+	.max_entries	= MAX_RULES,
+};
+
+static inline void count_shard(u16 le_port, u16 idx)
+{
+    struct shard_stats *stats;
+
+    stats = bpf_map_lookup_elem(&shard_stats_map, &le_port);
+    if (stats && idx < MAX_SHARDS) {
+        stats->shards[idx]++;
+    }
+}
+
+/* Count a packet for le_port that we did not rewrite. Does nothing if le_port is not sharded. */
+static inline void count_unsharded(u16 le_port, u32 reason)
+{
+    struct shard_stats *stats;
+
+    stats = bpf_map_lookup_elem(&shard_stats_map, &le_port);
+    if (stats && reason < NUM_UNSHARDED) {
+        stats->unsharded[reason]++;
+    }
+}
+
+/* Incrementally update a 16-bit one's complement checksum after a 16-bit field
+ * covered by it changes from old to new (RFC 1624, eqn. 3).
+ * All values are in network byte order.
+ */
+static inline u16 csum_replace2(u16 csum, u16 old, u16 new)
+{
+    u32 sum = (~((u32) csum)) & 0xffff;
+    sum += (~((u32) old)) & 0xffff;
+    sum += new;
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    return ~((u16) sum);
+}
+
+// hash_field result: the payload ends before the field, so the packet is passed unsharded.
+#define KEY_TOO_SHORT -1
+
+/* Feed field_size bytes at app_data + offset into the key hash and the sketch hash, and copy them
+ * to slot. le_port is the rule's port. Returns XDP_PASS to go on, KEY_TOO_SHORT, or the action to
+ * return for the packet.
+ */
+static inline int hash_field(void *app_data, void *data_end, struct shard_field *field, u16 le_port, struct hash_state *hash, u64 *sketch_hash, u8 *slot)
+{
     u8 offset;
+    u8 field_size;
     u8 *pkt_val;
-    u64 hash = FNV1_64_INIT;
-    u8 idx = 0;
     u8 i;
 
+    // check that the max offset we might have to read is valid
+    if (field->msg_offset > 64 || field->field_size < 1 || field->field_size > MAX_FIELD_SIZE) {
+        count_unsharded(le_port, UNSHARDED_BAD_RULE);
+        return drop(DROP_BAD_RULES, XDP_ABORTED);
+    } else {
+        offset = field->msg_offset;
+        field_size = field->field_size;
+    }
+
+    if (((void*) (offset + field_size + ((char*) app_data))) > data_end) {
+        count_unsharded(le_port, UNSHARDED_SHORT_MSG);
+        return KEY_TOO_SHORT;
+    }
+
+    // value start
+    pkt_val = ((u8*) app_data) + offset;
+
+    // copy field_size bytes to slot.
+    // the loop bound must be a constant for the verifier, so unroll to the max size and stop
+    // early. each byte gets its own bounds check, since the verifier can't use the variable-length
+    // check above.
+    #pragma clang loop unroll(full)
+    for (i = 0; i < MAX_FIELD_SIZE; i++) {
+        if (i >= field_size) {
+            break;
+        }
+
+        if ((void*) (pkt_val + i + 1) > data_end) {
+            count_unsharded(le_port, UNSHARDED_SHORT_MSG);
+            return KEY_TOO_SHORT;
+        }
+
+        *sketch_hash = (*sketch_hash ^ ((u64) pkt_val[i])) * FNV_64_PRIME;
+        slot[i] = pkt_val[i];
+    }
+
+    // then hash the copy, which needs no more bounds checks.
+    hash_update(hash, slot, field_size);
+    return XDP_PASS;
+}
+
+/* Look up the sharding rule for le_port. Returns 0 if the port is not sharded. */
+static inline struct available_shards *get_shards(u16 le_port)
+{
+    struct available_shards *shards;
+
     // lookup in the map of ports we have to do work for.
 	shards = bpf_map_lookup_elem(&available_shards_map, &le_port);
     if (!shards) {
-        bpf_printk("Could not get shards map for %u\n", le_port);
-        return XDP_PASS;
+        return 0;
     }
 
-    if (shards->num < 1 || shards->num > NUM_PORTS) {
+    if (shards->num < 1 || shards->num > MAX_SHARDS) {
         // sharding disabled
-        bpf_printk("Sharding disabled for %u: %u\n", le_port, shards->num);
-        return XDP_PASS;
+        count_unsharded(le_port, UNSHARDED_DISABLED);
+        return 0;
     }
 
-    // ok, we have to do work. first, check that the max offset we might have to read is valid
-    if (shards->rules.msg_offset > 64) {
-        bpf_printk("Shard rules invalid: %u,%u\n", shards->rules.msg_offset, shards->rules.field_size);
-        return XDP_ABORTED;
+    return shards;
+}
+
+/* Where a sharded packet goes. */
+struct shard_target {
+    u16 port; // host order
+    u16 idx;
+    u8 deliver; // DELIVER_*
+    u8 pad;
+    u16 cpu; // for DELIVER_CPU
+};
+
+/* Map a key hash to one of the rule's shard ports, placed according to mode (SHARD_MODE_*). */
+static inline int pick_shard(struct available_shards *shards, u8 mode, u16 le_port, u64 hash, struct shard_target *target)
+{
+    struct maglev_table *table;
+    struct range_splits *ranges;
+    u32 slot;
+    u16 idx = 0;
+    u16 lo, hi, mid;
+    u8 i;
+
+    if (mode == SHARD_MODE_MAGLEV) {
+        table = bpf_map_lookup_elem(&maglev_table_map, &le_port);
+        if (!table) {
+            count_unsharded(le_port, UNSHARDED_BAD_RULE);
+            return drop(DROP_NO_MAGLEV_TABLE, XDP_ABORTED);
+        }
+
+        slot = hash % MAGLEV_TABLE_SIZE;
+        if (slot >= MAGLEV_TABLE_SIZE) {
+            count_unsharded(le_port, UNSHARDED_BAD_RULE);
+            return drop(DROP_BAD_SHARD_IDX, XDP_ABORTED);
+        }
+
+        idx = table->slots[slot];
+    } else if (mode == SHARD_MODE_RANGE) {
+        ranges = bpf_map_lookup_elem(&range_splits_map, &le_port);
+        if (!ranges) {
+            count_unsharded(le_port, UNSHARDED_BAD_RULE);
+            return drop(DROP_NO_RANGE_SPLITS, XDP_ABORTED);
+        }
+
+        // binary search for the first split point > hash. 8 steps cover MAX_SHARDS - 1 splits.
+        lo = 0;
+        hi = shards->num - 1;
+        #pragma clang loop unroll(full)
+        for (i = 0; i < 8; i++) {
+            if (lo >= hi) {
+                break;
+            }
+
+            mid = (lo + hi) / 2;
+            if (mid >= MAX_SHARDS - 1) {
+                count_unsharded(le_port, UNSHARDED_BAD_RULE);
+                return drop(DROP_BAD_SHARD_IDX, XDP_ABORTED);
+            }
+
+            if (hash >= ranges->splits[mid]) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        idx = lo;
     } else {
-        offset = shards->rules.msg_offset;
+        idx = hash % shards->num;
     }
 
-    if (((void*) (offset + 4 + ((char*) app_data))) > data_end) {
-        bpf_printk("Packet not large enough for msg: %u\n", (data_end - app_data));
-        return XDP_ABORTED;
+    if (idx >= shards->num || idx >= MAX_SHARDS) {
+        count_unsharded(le_port, UNSHARDED_BAD_RULE);
+        return drop(DROP_BAD_SHARD_IDX, XDP_ABORTED);
     }
-    
-    // value start
-    pkt_val = ((u8*) app_data) + offset;
 
-    // compute FNV hash
-    #pragma clang loop unroll(full)
-    for (i = 0; i < 4; i++) {
-        hash = hash ^ ((u64) pkt_val[i]);
-        hash *= FNV_64_PRIME;
+    target->port = shards->ports[idx];
+    target->idx = idx;
+    target->deliver = shards->deliver;
+    target->pad = 0;
+    target->cpu = shards->cpus[idx];
+    return XDP_PASS;
+}
+
+static inline int deliver(struct shard_target *target, u32 rxq)
+{
+    if (target->deliver == DELIVER_CPU) {
+        return bpf_redirect_map(&cpu_map, target->cpu, 0);
     }
 
-    // map to a shard and assign to that port.
-    idx = hash % shards->num;
+    if (target->deliver == DELIVER_XSK) {
+        if ((rxq % RXQ_SLOTS) == MAX_RXQs) {
+            // shared slot for high queues, no sockets for it.
+            return XDP_PASS;
+        }
 
-    if (idx > 14) {
-        return XDP_ABORTED;
+        // if there is no socket for this queue, the lookup fails and we pass instead.
+        return bpf_redirect_map(&xsks_map, target->idx * MAX_IFACES * RXQ_SLOTS + rxq, XDP_PASS);
     }
 
-    out_port = shards->ports[idx];
-    bpf_printk("Sharding %u -> %u\n", le_port, out_port);
+    return XDP_PASS;
+}
+
+// csum is the L4 checksum covering *port. if csum_zero_ok, a zero checksum means
+// "no checksum" (UDP) and must be left alone.
+static inline void rewrite_port(u16 *port, u16 *csum, u8 csum_zero_ok, u16 out_port)
+{
+    if (*csum != 0 || !csum_zero_ok) {
+        *csum = csum_replace2(*csum, *port, htons(out_port));
+        if (*csum == 0 && csum_zero_ok) {
+            // a computed checksum of 0 is sent as all ones for UDP.
+            *csum = 0xffff;
+        }
+    }
 
     *port = htons(out_port);
-    return XDP_PASS;
 }
 
-static inline int record_port(u16 port, u32 rxq)
+/* Count key in its rule's sketch, and keep it as a hot key candidate once it is frequent. */
+static inline void record_key(struct override_key *key, u64 sketch_hash)
 {
-    struct datarec *rxq_rec;
+    struct key_sketch *sketch;
+    u32 h1 = (u32) sketch_hash;
+    u32 h2 = (u32) (sketch_hash >> 32);
+    u64 estimate = ~0ULL;
+    u32 col;
     u8 i;
-    u16 *ports;
-    u32 *counts;
-	rxq_rec = bpf_map_lookup_elem(&rx_queue_index_map, &rxq);
-	if (!rxq_rec)
-		return XDP_ABORTED;
 
-    ports = rxq_rec->ports;
-    counts = rxq_rec->counts;
+    sketch = bpf_map_lookup_elem(&key_sketch_map, &key->port);
+    if (!sketch) {
+        return;
+    }
+
+    #pragma clang loop unroll(full)
+    for (i = 0; i < SKETCH_DEPTH; i++) {
+        col = (h1 + i * h2) & (SKETCH_WIDTH - 1);
+        __sync_fetch_and_add(&sketch->counts[i][col], 1);
+        if (sketch->counts[i][col] < estimate) {
+            estimate = sketch->counts[i][col];
+        }
+    }
 
-    for (i = 0; i < NUM_PORTS; i++) {
-        // we've seen this port before
-        if (ports[i] == port) {
-            counts[i]++;
-            return XDP_PASS;
+    if (estimate < SKETCH_HOT_MIN) {
+        return;
+    }
+
+    bpf_map_update_elem(&hot_key_map, key, &estimate, BPF_ANY);
+}
+
+/* TCP connection 4-tuple, as it arrives (i.e., before rewriting). IPv4 addresses use [0]. */
+struct tcp_flow_key {
+    u32 saddr[4];
+    u32 daddr[4];
+    u16 sport;
+    u16 dport;
+};
+
+/* Emit 1 in sample_rate_map[0] sharding decisions to sample_map. key and rules are 0 for
+ * TCP, which has no key.
+ */
+static inline void sample_decision(struct tcp_flow_key *flow, u8 flags, u16 le_port, struct override_key *key, struct shard_rules *rules, u64 hash, u16 out_port, u16 idx, u32 rxq)
+{
+#ifdef SAMPLE_PERF
+    struct sample_scratch *scratch;
+#endif
+    struct shard_sample *sample;
+    u32 zero = 0;
+    u32 *every;
+    u8 i;
+
+    every = bpf_map_lookup_elem(&sample_rate_map, &zero);
+    if (!every || *every == 0 || ((u32) bpf_get_prandom_u32()) % *every != 0) {
+        return;
+    }
+
+#ifdef SAMPLE_PERF
+    scratch = bpf_map_lookup_elem(&sample_scratch_map, &zero);
+    if (!scratch) {
+        return;
+    }
+
+    sample = &scratch->sample;
+#else
+    sample = bpf_ringbuf_reserve(&sample_map, sizeof(*sample), 0);
+    if (!sample) {
+        // full: userspace is behind, so drop the sample.
+        return;
+    }
+#endif
+
+    __builtin_memset(sample, 0, sizeof(*sample));
+    __builtin_memcpy(sample->saddr, flow->saddr, sizeof(sample->saddr));
+    sample->hash = hash;
+    sample->rxq = rxq;
+    sample->cpu = bpf_get_smp_processor_id();
+    sample->orig_port = le_port;
+    sample->port = out_port;
+    sample->idx = idx;
+    sample->flags = flags;
+
+    if (key && rules) {
+        __builtin_memcpy(sample->key, key->fields, sizeof(sample->key));
+        #pragma clang loop unroll(full)
+        for (i = 0; i < MAX_KEY_FIELDS; i++) {
+            if (i >= rules->num_fields) {
+                break;
+            }
+
+            sample->field_sizes[i] = rules->fields[i].field_size;
         }
+    }
+
+#ifdef SAMPLE_PERF
+    scratch->pending = 1;
+#else
+    bpf_ringbuf_submit(sample, 0);
+#endif
+}
+
+/* Send the packet's sample, if sample_decision left one. Ring buffer samples are already sent. */
+static inline void flush_sample(struct xdp_md *ctx)
+{
+#ifdef SAMPLE_PERF
+    struct sample_scratch *scratch;
+    u32 zero = 0;
+
+    scratch = bpf_map_lookup_elem(&sample_scratch_map, &zero);
+    if (!scratch || !scratch->pending) {
+        return;
+    }
+
+    scratch->pending = 0;
+    bpf_perf_event_output(ctx, &sample_map, BPF_F_CURRENT_CPU, &scratch->sample, sizeof(scratch->sample));
+#endif
+}
 
-        // we've gotten this far and a slot is free, fill it.
-        if (ports[i] == 0) {
-            ports[i] = port;
-            counts[i] = 1;
+// flow has the source address filled in. sample_flags is SAMPLE_IPV6 or 0.
+static inline int shard_generic(void *app_data, void *data_end, u16 *port, u16 *csum, u8 csum_zero_ok, u32 rxq, struct tcp_flow_key *flow, u8 sample_flags) {
+    struct available_shards *shards;
+    struct shard_target target;
+    struct hash_state hash;
+    struct override_key okey;
+    struct override_rec *override;
+    u64 sketch_hash = FNV1A_64_INIT;
+    u64 key_hash;
+    u16 le_port = ntohs(*port);
+    u32 key_len = 0;
+    u8 i;
+    int res;
+
+    shards = get_shards(le_port);
+    if (!shards) {
+        return XDP_PASS;
+    }
+
+    // ok, we have to do work.
+    if (shards->rules.num_fields < 1 || shards->rules.num_fields > MAX_KEY_FIELDS || shards->rules.hash >= NUM_HASHES) {
+        count_unsharded(le_port, UNSHARDED_BAD_RULE);
+        return drop(DROP_BAD_RULES, XDP_ABORTED);
+    }
+
+    // some hashes need the key length up front. bad field sizes are caught in hash_field.
+    #pragma clang loop unroll(full)
+    for (i = 0; i < MAX_KEY_FIELDS; i++) {
+        if (i >= shards->rules.num_fields) {
+            break;
+        }
+
+        key_len += shards->rules.fields[i].field_size;
+    }
+
+    hash_init(&hash, shards->rules.hash, shards->rules.hash_key[0], shards->rules.hash_key[1], key_len);
+    __builtin_memset(&okey, 0, sizeof(okey));
+    okey.port = le_port;
+
+    // hash the key fields in order.
+    #pragma clang loop unroll(full)
+    for (i = 0; i < MAX_KEY_FIELDS; i++) {
+        if (i >= shards->rules.num_fields) {
+            break;
+        }
+
+        res = hash_field(app_data, data_end, &shards->rules.fields[i], le_port, &hash, &sketch_hash, okey.fields[i]);
+        if (res == KEY_TOO_SHORT) {
             return XDP_PASS;
+        } else if (res != XDP_PASS) {
+            return res;
         }
     }
 
-    // didn't find the port, and didn't find a free slot.
-    // we have to dump it in the leftovers bin.
-    counts[NUM_PORTS]++;
-    return XDP_PASS;
+    if (shards->track_keys) {
+        record_key(&okey, sketch_hash);
+    }
+
+    // hot keys pinned to a port skip the hash. they go to the stack whatever the rule's delivery,
+    // since the port need not be one of the rule's shards.
+    override = bpf_map_lookup_elem(&key_override_map, &okey);
+    if (override) {
+        __sync_fetch_and_add(&override->hits, 1);
+        sample_decision(flow, sample_flags | SAMPLE_PINNED, le_port, &okey, &shards->rules, 0, override->port, 0, rxq);
+        rewrite_port(port, csum, csum_zero_ok, override->port);
+        return XDP_PASS;
+    }
+
+    // map to a shard and assign to that port.
+    key_hash = hash_final(&hash);
+    res = pick_shard(shards, shards->mode, le_port, key_hash, &target);
+    if (res != XDP_PASS) {
+        return res;
+    }
+
+    count_shard(le_port, target.idx);
+    sample_decision(flow, sample_flags, le_port, &okey, &shards->rules, key_hash, target.port, target.idx, rxq);
+    rewrite_port(port, csum, csum_zero_ok, target.port);
+    return deliver(&target, rxq);
+}
+
+/* TCP connection -> shard it was assigned at SYN time */
+struct bpf_map_def SEC("maps") tcp_flow_map = {
+	.type		= BPF_MAP_TYPE_LRU_HASH,
+	.key_size	= sizeof(struct tcp_flow_key),
+	.value_size	= sizeof(struct shard_target),
+	.max_entries	= 65536,
+};
+
+/* Rewriting the port of a segment in the middle of a connection breaks it, so for TCP the
+ * shard is picked once, on the SYN, from a hash of the 4-tuple: the payload key isn't available
+ * yet. The choice is remembered in tcp_flow_map and every later segment of the connection gets
+ * the same rewrite. Connections whose SYN we didn't shard are left alone.
+ */
+static inline int shard_tcp(struct tcphdr *th, struct tcp_flow_key *flow, u32 rxq, u8 sample_flags)
+{
+    struct available_shards *shards;
+    u16 le_port = ntohs(th->dest);
+    struct shard_target *flow_target;
+    struct shard_target target;
+    struct hash_state hash;
+    u64 flow_hash;
+    u8 *flow_bytes = (u8*) flow;
+    u8 mode;
+    u8 i;
+    int res;
+
+    flow_target = bpf_map_lookup_elem(&tcp_flow_map, flow);
+    if (flow_target) {
+        count_shard(le_port, flow_target->idx);
+        rewrite_port(&(th->dest), &(th->check), 0, flow_target->port);
+        return deliver(flow_target, rxq);
+    }
+
+    if (!th->syn || th->ack) {
+        count_unsharded(le_port, UNSHARDED_TCP_MIDFLOW);
+        return XDP_PASS;
+    }
+
+    shards = get_shards(le_port);
+    if (!shards) {
+        return XDP_PASS;
+    }
+
+    // the 4-tuple is always hashed with FNV-1a: the rule's hash is for its payload key.
+    hash_init(&hash, HASH_FNV1A, 0, 0, sizeof(*flow));
+    #pragma clang loop unroll(full)
+    for (i = 0; i < sizeof(*flow); i++) {
+        fnv1a_update(&hash, flow_bytes[i]);
+    }
+
+    flow_hash = hash_final(&hash);
+
+    // range split points are key values, which a 4-tuple hash is not: range rules spread
+    // connections by hash % num instead.
+    mode = shards->mode == SHARD_MODE_RANGE ? SHARD_MODE_MODULO : shards->mode;
+    res = pick_shard(shards, mode, le_port, flow_hash, &target);
+    if (res != XDP_PASS) {
+        return res;
+    }
+
+    bpf_map_update_elem(&tcp_flow_map, flow, &target, BPF_ANY);
+    count_shard(le_port, target.idx);
+    sample_decision(flow, sample_flags | SAMPLE_TCP, le_port, 0, 0, flow_hash, target.port, target.idx, rxq);
+    rewrite_port(&(th->dest), &(th->check), 0, target.port);
+    return deliver(&target, rxq);
 }
 
-static inline int record_icmp(u32 rxq)
+static inline void record_port(u16 port, u8 proto, u32 rxq, u32 pkt_len)
+{
+    struct port_key key = {
+        .rxq = rxq,
+        .port = port,
+        .proto = proto,
+        .pad = 0,
+    };
+    struct port_rec new_rec = {
+        .count = 1,
+        .bytes = pkt_len,
+    };
+    struct port_rec *rec;
+
+    rec = bpf_map_lookup_elem(&port_stats_map, &key);
+    if (rec) {
+        rec->count++;
+        rec->bytes += pkt_len;
+        return;
+    }
+
+    // first packet to this port on this cpu since the entry was created (or evicted).
+    bpf_map_update_elem(&port_stats_map, &key, &new_rec, BPF_NOEXIST);
+}
+
+static inline int record_ip(u32 rxq, u32 which)
 {
     struct datarec *rxq_rec;
 	rxq_rec = bpf_map_lookup_elem(&rx_queue_index_map, &rxq);
 	if (!rxq_rec)
-		return XDP_ABORTED;
+		return drop(DROP_NO_RXQ_RECORD, XDP_ABORTED);
 
-    // no port, stick it in the leftover bin.
-    rxq_rec->counts[NUM_PORTS]++;
+    if (which >= NUM_IP_COUNTS)
+        return XDP_ABORTED;
+
+    rxq_rec->ip_counts[which]++;
     return XDP_PASS;
 }
 
-static inline int parse_tcp(void *tcp_data, void *data_end, u32 rxq)
+// flow has the IP addresses filled in. sample_flags is SAMPLE_IPV6 or 0.
+static inline int parse_tcp(void *tcp_data, void *data_end, u32 rxq, u32 pkt_len, struct tcp_flow_key *flow, u8 sample_flags)
 {
     struct tcphdr *th;
     u16 port;
-    int res;
-    u8 data_offset;
-    void *payload;
 
     th = (struct tcphdr *)tcp_data;
     // check the TCP header itself
     if ((th + 1) > (struct tcphdr*) data_end)
-        return XDP_ABORTED;
-    data_offset = ntohs(th->doff);
-    if (data_offset > 0xf) {
-        return XDP_ABORTED; // 4-bit bitfield
+        return drop(DROP_SHORT_PACKET, XDP_ABORTED);
+
+    // doff is the header length in 32-bit words, including options.
+    if (th->doff < 5) {
+        return drop(DROP_BAD_TCP_HDR, XDP_ABORTED);
     }
 
-    payload = ((char*)tcp_data) + data_offset * sizeof(u32);
     // check stated payload location
-    if (payload > data_end) {
-        return XDP_ABORTED;
+    if ((void*) (((char*)tcp_data) + th->doff * 4) > data_end) {
+        return drop(DROP_SHORT_PACKET, XDP_ABORTED);
     }
 
     port = ntohs(th->dest);
-    res = record_port(port, rxq);
-    if (res == XDP_ABORTED) { return res; }
-    return shard_generic(payload, data_end, &(th->dest));
+    record_port(port, IPPROTO_TCP, rxq, pkt_len);
+
+    flow->sport = th->source;
+    flow->dport = th->dest;
+    return shard_tcp(th, flow, rxq, sample_flags);
 }
 
-static inline int parse_udp(void *udp_data, void *data_end, u32 rxq)
+// like parse_tcp, but flow is only used for its addresses.
+static inline int parse_udp(void *udp_data, void *data_end, u32 rxq, u32 pkt_len, struct tcp_flow_key *flow, u8 sample_flags)
 {
-    int res;
     struct udphdr *uh;
     u16 port;
     uh = (struct udphdr *)udp_data;
     if ((uh + 1) > (struct udphdr*) data_end)
-        return XDP_ABORTED;
+        return drop(DROP_SHORT_PACKET, XDP_ABORTED);
 
     port = ntohs(uh->dest);
-    res = record_port(port, rxq);
-    if (res == XDP_ABORTED) { return res; }
-    return shard_generic((void*) (uh + 1), data_end, &(uh->dest));
+    record_port(port, IPPROTO_UDP, rxq, pkt_len);
+    return shard_generic((void*) (uh + 1), data_end, &(uh->dest), &(uh->check), 1, rxq, flow, sample_flags);
 }
 
-static inline int parse_ipv4(void *data, void *data_end, u32 rxq)
+#define IPV4_FRAG_OFFSET_MASK 0x1fff
+
+static inline int parse_ipv4(void *data, void *data_end, u32 rxq, u32 pkt_len)
 {
     void *trans_data;
     struct iphdr *iph = data;
-    trans_data = iph + 1;
-    if (trans_data > data_end)
-        return XDP_ABORTED;
+    struct tcp_flow_key flow = {};
+    if ((void*) (iph + 1) > data_end)
+        return drop(DROP_SHORT_PACKET, XDP_ABORTED);
+
+    // the header is ihl 32-bit words long, including any options.
+    if (iph->ihl < 5) {
+        record_ip(rxq, IP_COUNT_BAD_HDR);
+        return drop(DROP_BAD_IP_HDR, XDP_DROP);
+    }
 
+    trans_data = ((char*) data) + iph->ihl * 4;
+    if (trans_data > data_end) {
+        record_ip(rxq, IP_COUNT_BAD_HDR);
+        return drop(DROP_BAD_IP_HDR, XDP_DROP);
+    }
+
+    if (iph->ihl > 5) {
+        record_ip(rxq, IP_COUNT_OPTIONS);
+    }
+
+    if (ntohs(iph->frag_off) & IPV4_FRAG_OFFSET_MASK) {
+        // not the first fragment, so no transport header.
+        record_ip(rxq, IP_COUNT_FRAGMENT);
+        return XDP_PASS;
+    }
+
+    flow.saddr[0] = iph->saddr;
+    flow.daddr[0] = iph->daddr;
     if (iph->protocol == IPPROTO_TCP) {
-        return parse_tcp(trans_data, data_end, rxq);
+        return parse_tcp(trans_data, data_end, rxq, pkt_len, &flow, 0);
     } else if (iph->protocol ==IPPROTO_UDP) {
-        return parse_udp(trans_data, data_end, rxq);
+        return parse_udp(trans_data, data_end, rxq, pkt_len, &flow, 0);
     } else if (iph->protocol == IPPROTO_ICMP) {
-        record_icmp(rxq);
+        record_port(0, IPPROTO_ICMP, rxq, pkt_len);
         return XDP_PASS;
     } else {
         return XDP_PASS;
     }
 }
 
+/* Max number of IPv6 extension headers we will walk before giving up */
+#define MAX_IPV6_EXT_HDRS 6
+
+struct ipv6_frag_hdr {
+    u8 nexthdr;
+    u8 reserved;
+    u16 frag_off;
+    u32 identification;
+};
+
+#define IPV6_FRAG_OFFSET_MASK 0xfff8
+
+static inline int parse_ipv6(void *data, void *data_end, u32 rxq, u32 pkt_len)
+{
+    void *trans_data;
+    struct ipv6hdr *ip6h = data;
+    struct ipv6_opt_hdr *opth;
+    struct ipv6_frag_hdr *fragh;
+    struct tcp_flow_key flow = {};
+    u8 nexthdr;
+    u8 i;
+
+    trans_data = ip6h + 1;
+    if (trans_data > data_end)
+        return drop(DROP_SHORT_PACKET, XDP_ABORTED);
+
+    nexthdr = ip6h->nexthdr;
+
+    // walk the extension header chain until we find the transport header.
+    #pragma clang loop unroll(full)
+    for (i = 0; i < MAX_IPV6_EXT_HDRS; i++) {
+        if (nexthdr == IPPROTO_HOPOPTS || nexthdr == IPPROTO_ROUTING || nexthdr == IPPROTO_DSTOPTS) {
+            opth = trans_data;
+            if ((void*) (opth + 1) > data_end)
+                return drop(DROP_SHORT_PACKET, XDP_ABORTED);
+            nexthdr = opth->nexthdr;
+            trans_data = ((char*) trans_data) + ((opth->hdrlen + 1) << 3);
+        } else if (nexthdr == IPPROTO_AH) {
+            opth = trans_data;
+            if ((void*) (opth + 1) > data_end)
+                return drop(DROP_SHORT_PACKET, XDP_ABORTED);
+            nexthdr = opth->nexthdr;
+            trans_data = ((char*) trans_data) + ((opth->hdrlen + 2) << 2);
+        } else if (nexthdr == IPPROTO_FRAGMENT) {
+            fragh = trans_data;
+            if ((void*) (fragh + 1) > data_end)
+                return drop(DROP_SHORT_PACKET, XDP_ABORTED);
+            if (ntohs(fragh->frag_off) & IPV6_FRAG_OFFSET_MASK) {
+                // not the first fragment, so no transport header.
+                record_ip(rxq, IP_COUNT_FRAGMENT);
+                return XDP_PASS;
+            }
+
+            nexthdr = fragh->nexthdr;
+            trans_data = fragh + 1;
+        } else {
+            break;
+        }
+    }
+
+    if (trans_data > data_end)
+        return drop(DROP_SHORT_PACKET, XDP_ABORTED);
+
+    __builtin_memcpy(flow.saddr, ip6h->saddr.in6_u.u6_addr32, sizeof(flow.saddr));
+    __builtin_memcpy(flow.daddr, ip6h->daddr.in6_u.u6_addr32, sizeof(flow.daddr));
+    if (nexthdr == IPPROTO_TCP) {
+        return parse_tcp(trans_data, data_end, rxq, pkt_len, &flow, SAMPLE_IPV6);
+    } else if (nexthdr == IPPROTO_UDP) {
+        return parse_udp(trans_data, data_end, rxq, pkt_len, &flow, SAMPLE_IPV6);
+    } else if (nexthdr == IPPROTO_ICMPV6) {
+        record_port(0, IPPROTO_ICMPV6, rxq, pkt_len);
+        return XDP_PASS;
+    } else {
+        return XDP_PASS;
+    }
+}
+
+/* 802.1Q / 802.1ad tag, following the MAC addresses */
+struct vlan_hdr {
+    u16 h_vlan_TCI;
+    u16 h_vlan_encapsulated_proto;
+};
+
+/* Max number of VLAN tags (QinQ) we will step over */
+#define MAX_VLAN_TAGS 2
+
 static inline int parse_eth(void *data, void *data_end, u32 rxq)
 {
     u16 h_proto;
     u64 nh_off;
     struct ethhdr *eth = data;
+    struct vlan_hdr *vlh;
+    u32 pkt_len = data_end - data;
+    u8 i;
 
     nh_off = sizeof(*eth);
     if (data + nh_off > data_end) {
         // need to be at least big enough for the eth header
-        return XDP_ABORTED;
+        return drop(DROP_SHORT_PACKET, XDP_ABORTED);
     }
 
     h_proto = eth->h_proto;
-    if (h_proto == htons(ETH_P_8021Q) || h_proto == htons(ETH_P_8021AD) || h_proto == htons(ETH_P_ARP)) {
+
+    // step over the vlan tags, if any.
+    #pragma clang loop unroll(full)
+    for (i = 0; i < MAX_VLAN_TAGS; i++) {
+        if (h_proto != htons(ETH_P_8021Q) && h_proto != htons(ETH_P_8021AD)) {
+            break;
+        }
+
+        vlh = (struct vlan_hdr*) (((char*)data) + nh_off);
+        if ((void*) (vlh + 1) > data_end) {
+            return drop(DROP_SHORT_PACKET, XDP_ABORTED);
+        }
+
+        h_proto = vlh->h_vlan_encapsulated_proto;
+        nh_off += sizeof(*vlh);
+    }
+
+    if (h_proto == htons(ETH_P_ARP)) {
         return XDP_PASS;
     }
     
     if (h_proto == htons(ETH_P_IP)) {
-        return parse_ipv4(((char*)data) + nh_off, data_end, rxq);
+        return parse_ipv4(((char*)data) + nh_off, data_end, rxq, pkt_len);
+    }
+
+    if (h_proto == htons(ETH_P_IPV6)) {
+        return parse_ipv6(((char*)data) + nh_off, data_end, rxq, pkt_len);
     }
 
     return XDP_PASS;
@@ -252,8 +940,10 @@ static inline int parse_eth(void *data, void *data_end, u32 rxq)
 SEC("xdp_steer_prog")
 int  xdp_steer(struct xdp_md *ctx)
 {
-	int *expected_ifindex, ingress_ifindex;
-	__u32 rxq = 0;
+	int ingress_ifindex;
+	__u32 *iface;
+	__u32 rxq;
+    int action;
     void *data_end = (void *)(long)ctx->data_end;
     void *data = (void *)(long)ctx->data;
 
@@ -261,14 +951,14 @@ int  xdp_steer(struct xdp_md *ctx)
 	 * instructions inside kernel to access xdp_rxq->dev->ifindex
 	 */
 	ingress_ifindex = ctx->ingress_ifindex;
-	expected_ifindex = bpf_map_lookup_elem(&ifindex_map, &rxq);
+	iface = bpf_map_lookup_elem(&ifindex_map, &ingress_ifindex);
 
-	/* Simple test: check ctx provided ifindex is as expected */
-	if (!expected_ifindex || ingress_ifindex != *expected_ifindex) {
-		return XDP_ABORTED;
+	/* Simple test: check ctx provided ifindex is one we were loaded on */
+	if (!iface || *iface >= MAX_IFACES) {
+		return drop(DROP_BAD_IFINDEX, XDP_ABORTED);
 	}
 
-	/* Update stats per rx_queue_index. Handle if rx_queue_index
+	/* Update stats per rxq slot. Handle if rx_queue_index
 	 * is larger than stats map can contain info for.
 	 */
 	rxq = ctx->rx_queue_index;
@@ -276,7 +966,11 @@ int  xdp_steer(struct xdp_md *ctx)
 		rxq = MAX_RXQs;
     }
 
-    return parse_eth(data, data_end, rxq);
+	rxq += *iface * RXQ_SLOTS;
+
+    action = parse_eth(data, data_end, rxq);
+    flush_sample(ctx);
+    return action;
 }
 
 char _license[] SEC("license") = "GPL";
@@ -1,19 +1,165 @@
 /* Map value types shared with userspace. */
 #include <linux/types.h>
 
-#define NUM_PORTS 16
+// ip_counts[] indices
+#define IP_COUNT_OPTIONS 0  // IPv4 packets with IP options
+#define IP_COUNT_BAD_HDR 1  // IPv4 packets with an invalid header length, dropped
+#define IP_COUNT_FRAGMENT 2 // non-first fragments, which carry no L4 header
+#define NUM_IP_COUNTS 3
+
+// drop_reason_map indices: why the program returned XDP_ABORTED or XDP_DROP
+#define DROP_SHORT_PACKET 0    // too short for a header it claims to have
+#define DROP_BAD_RULES 1       // invalid shard rule
+#define DROP_NO_MAGLEV_TABLE 2 // maglev rule without a lookup table
+#define DROP_BAD_SHARD_IDX 3   // shard index out of range
+#define DROP_BAD_IFINDEX 4     // packet from an interface we were not loaded on
+#define DROP_NO_RXQ_RECORD 5   // no stats record for the rx queue
+#define DROP_BAD_IP_HDR 6      // IPv4 header length invalid
+#define DROP_BAD_TCP_HDR 7     // TCP data offset invalid
+#define DROP_NO_RANGE_SPLITS 8 // range rule without split points
+#define NUM_DROP_REASONS 9
+
 struct datarec {
-    __u16 ports[NUM_PORTS];
-    __u32 counts[NUM_PORTS + 1];
+    __u64 ip_counts[NUM_IP_COUNTS];
+};
+
+// port_stats_map key. Packets without a port (ICMP) use port 0.
+struct port_key {
+    __u32 rxq; // rxq slot
+    __u16 port;
+    __u8 proto; // IPPROTO_*
+    __u8 pad;
+};
+
+struct port_rec {
+    __u64 count;
+    __u64 bytes; // total packet (frame) length
+};
+
+#define MAX_FIELD_SIZE 16
+struct shard_field {
+    __u8 msg_offset; // where in the message does the field start? (fixed location)
+    __u8 field_size; // field length in bytes, 1..=MAX_FIELD_SIZE
 };
 
+#define MAX_KEY_FIELDS 4
+
+// shard_rules.hash: how the key is hashed. see hash.h
+#define HASH_FNV1A 0     // 64-bit FNV-1a
+#define HASH_SIPHASH13 1 // SipHash-1-3, keyed with hash_key
+#define HASH_CRC32 2     // CRC-32 (IEEE)
+#define HASH_XXHASH32 3  // xxHash32, seeded with the low 32 bits of hash_key[0]
+#define HASH_IDENTITY 4  // the key itself as a big-endian integer (its last 8 bytes, if longer)
+#define HASH_IDENTITY_LE 5 // the key itself as a little-endian integer (its first 8 bytes, if longer)
+#define NUM_HASHES 6
+
 struct shard_rules {
-    __u8 msg_offset; // where in the message does the key start? (fixed location)
-    __u8 field_size; // if static - easy mode TODO ignored (verifier)
+    __u8 num_fields; // the key is fields[0..num_fields], hashed in order
+    struct shard_field fields[MAX_KEY_FIELDS];
+    __u8 hash; // HASH_*
+    __u64 hash_key[2];
 };
 
+#define SHARD_MODE_MODULO 0 // idx = hash % num
+#define SHARD_MODE_MAGLEV 1 // idx = maglev_table.slots[hash % MAGLEV_TABLE_SIZE]
+#define SHARD_MODE_RANGE 2  // idx = number of range_splits.splits <= hash. the hash is HASH_IDENTITY*,
+                            // so this partitions the key as an integer. TCP SYNs use SHARD_MODE_MODULO
+
+#define DELIVER_STACK 0 // pass to the kernel stack on the current cpu
+#define DELIVER_CPU 1   // redirect shard i's packets to cpus[i] through cpu_map
+#define DELIVER_XSK 2   // redirect shard i's packets to its AF_XDP socket for the packet's rxq slot,
+                        // xsks_map[i * MAX_IFACES * RXQ_SLOTS + rxq slot]
+
+#define MAX_RXQs 64
+// Stats are kept per (interface, rx queue) "rxq slot": iface * RXQ_SLOTS + rxq, where iface is
+// the interface's ifindex_map value. Queues >= MAX_RXQs share the last slot of their interface.
+#define RXQ_SLOTS (MAX_RXQs + 1)
+#define MAX_IFACES 8
+#define MAX_SHARDS 128
+#define MAX_CPUS 256
 struct available_shards {
     __u8 num;
-    __u16 ports[16]; // max 16 shards
+    __u16 ports[MAX_SHARDS];
     struct shard_rules rules;
+    __u8 mode; // SHARD_MODE_*
+    __u8 deliver; // DELIVER_*
+    __u16 cpus[MAX_SHARDS];
+    __u8 track_keys; // count the rule's keys in key_sketch_map, to find hot keys
+};
+
+// shard_stats unsharded[] indices: why a packet for a sharded port was not rewritten
+#define UNSHARDED_DISABLED 0    // the rule has no shards
+#define UNSHARDED_SHORT_MSG 1   // payload too short for the shard key
+#define UNSHARDED_BAD_RULE 2    // invalid rule, or no maglev table or range splits for it
+#define UNSHARDED_TCP_MIDFLOW 3 // TCP segment of a connection whose SYN was not sharded
+#define NUM_UNSHARDED 4
+
+struct shard_stats {
+    __u64 shards[MAX_SHARDS]; // packets rewritten to each shard, indexed like available_shards.ports
+    __u64 unsharded[NUM_UNSHARDED];
+};
+
+// max number of orig_ports with sharding rules
+#define MAX_RULES 64
+
+// sorted split points of a SHARD_MODE_RANGE rule with num shards: shard i gets the keys in
+// [splits[i - 1], splits[i]), and splits[num - 1..] are unused.
+struct range_splits {
+    __u64 splits[MAX_SHARDS - 1];
+};
+
+// key_override_map key: a rule's shard key, each field in its own zero-padded slot.
+#define MAX_OVERRIDES 1024
+struct override_key {
+    __u16 port; // the rule's orig_port
+    __u8 pad[2];
+    __u8 fields[MAX_KEY_FIELDS][MAX_FIELD_SIZE];
+};
+
+struct override_rec {
+    __u64 hits;
+    __u16 port; // rewrite to this port instead of hashing
+    __u8 pad[6];
+};
+
+// count-min sketch of a rule's shard keys, to find hot keys. Row i counts key k in column
+// (h1 + i * h2) % SKETCH_WIDTH, where h2:h1 is the 64-bit FNV-1a hash of k.
+#define SKETCH_DEPTH 4
+#define SKETCH_WIDTH 1024 // power of 2
+#define SKETCH_HOT_MIN 64 // keys become hot key candidates once their estimate reaches this
+struct key_sketch {
+    __u64 counts[SKETCH_DEPTH][SKETCH_WIDTH];
+};
+
+// hot key candidates, across all rules
+#define MAX_HOT_KEYS 4096
+
+// sampled sharding decisions, streamed to userspace through sample_map: a ring buffer, or with
+// SAMPLE_PERF (for kernels before 5.8, which have no ring buffers) one perf buffer per CPU.
+#define SAMPLE_RINGBUF_SIZE (256 * 1024) // power of 2, and a multiple of the page size
+#define SAMPLE_PERF_PAGES 16 // per CPU, a power of 2
+
+// shard_sample.flags
+#define SAMPLE_IPV6 1   // saddr is IPv6
+#define SAMPLE_TCP 2    // a TCP SYN, sharded on its 4-tuple: there is no key
+#define SAMPLE_PINNED 4 // the key is pinned in key_override_map: hash and idx are unused
+
+struct shard_sample {
+    __u32 saddr[4]; // network order. IPv4 uses [0]
+    __u64 hash;
+    __u32 rxq; // rxq slot
+    __u32 cpu;
+    __u16 orig_port;
+    __u16 port; // the port it was rewritten to
+    __u16 idx; // shard index
+    __u8 flags; // SAMPLE_*
+    __u8 pad;
+    __u8 field_sizes[MAX_KEY_FIELDS]; // 0 for unused fields
+    __u8 key[MAX_KEY_FIELDS][MAX_FIELD_SIZE]; // each field in its own slot, as in override_key
+};
+
+// prime, and much larger than MAX_SHARDS.
+#define MAGLEV_TABLE_SIZE 65537
+struct maglev_table {
+    __u16 slots[MAGLEV_TABLE_SIZE]; // index into available_shards.ports
 };
@@ -0,0 +1,449 @@
+//! AF_XDP delivery: the XDP program redirects each shard's packets into AF_XDP sockets through
+//! `xsks_map`, so a server gets them without going through the kernel UDP stack.
+//!
+//! An AF_XDP socket only receives from the rx queue it is bound to, so every shard gets one
+//! socket per rx queue. The kernel binds only one UMEM to a queue, so the shards' sockets on a
+//! queue share one, registered on the first of them, along with its fill ring. This only
+//! receives, so there are no TX rings.
+
+use crate::bindings::if_xdp;
+use crate::{BpfHandles, StdError};
+use nix::libc;
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+// not in every libc version.
+const AF_XDP: c_int = 44;
+const SOL_XDP: c_int = 283;
+
+const FRAME_SIZE: usize = 2048;
+const NUM_FRAMES: usize = 4096;
+// ring sizes must be powers of two. The fill ring holds every frame.
+const FILL_RING_SIZE: u32 = NUM_FRAMES as u32;
+const COMP_RING_SIZE: u32 = 64;
+const RX_RING_SIZE: u32 = 2048;
+const POLL_TIMEOUT_MS: c_int = 100;
+
+/// Receives a sharded port's packets on AF_XDP sockets.
+///
+/// On drop, closes the sockets. The kernel then removes them from `xsks_map`, and the XDP
+/// program passes the shards' packets to the kernel stack again.
+#[derive(Debug)]
+pub struct XskShardServer {
+    orig_port: u16,
+    queues: Vec<QueueXsks>,
+}
+
+impl XskShardServer {
+    /// Create a socket for each shard of `orig_port` on each of the rx queues `0..num_queues` of
+    /// each interface `handles` is loaded on, and switch `orig_port` to AF_XDP delivery.
+    ///
+    /// `orig_port` must already be sharded. Like [`BpfHandles::redirect_shards_to_cpus`], this
+    /// has to be set up again after changing the shards.
+    pub fn new(
+        handles: &mut BpfHandles,
+        orig_port: u16,
+        num_queues: u32,
+    ) -> Result<Self, StdError> {
+        let num_shards = match handles.num_shards(orig_port) {
+            Some(n) => n,
+            None => Err(format!("Port is not sharded: {}", orig_port))?,
+        };
+
+        if num_queues == 0 || num_queues > crate::xdp_shard::MAX_RXQs {
+            Err(format!(
+                "Invalid number of rx queues (must be 1..={}): {}",
+                crate::xdp_shard::MAX_RXQs,
+                num_queues
+            ))?;
+        }
+
+        let interfaces = handles.interfaces();
+        let mut queues = Vec::with_capacity(interfaces.len() * num_queues as usize);
+        for (iface, &(ifindex, _)) in interfaces.iter().enumerate() {
+            for queue in 0..num_queues {
+                let q = QueueXsks::new(ifindex, queue, num_shards)?;
+                for xsk in q.xsks.iter() {
+                    handles.set_xsk(xsk.shard, iface, queue, xsk.fd.0)?;
+                }
+
+                queues.push(q);
+            }
+        }
+
+        handles.deliver_to_xsks(orig_port, true)?;
+        Ok(XskShardServer { orig_port, queues })
+    }
+
+    pub fn orig_port(&self) -> u16 {
+        self.orig_port
+    }
+
+    /// Receive packets until `stop` is set.
+    ///
+    /// `f` is called with the shard index and the whole Ethernet frame, after the XDP program
+    /// rewrote its destination port. The frame is given back to the kernel when `f` returns.
+    pub fn run(
+        &mut self,
+        stop: &AtomicBool,
+        mut f: impl FnMut(usize, &[u8]),
+    ) -> Result<(), StdError> {
+        let mut pollfds: Vec<libc::pollfd> = self
+            .queues
+            .iter()
+            .flat_map(|q| q.xsks.iter())
+            .map(|x| libc::pollfd {
+                fd: x.fd.0,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        while !stop.load(Ordering::SeqCst) {
+            let ok =
+                unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as _, POLL_TIMEOUT_MS) };
+            if ok < 0 {
+                let errno = nix::errno::Errno::last();
+                if errno == nix::errno::Errno::EINTR {
+                    continue;
+                }
+
+                Err(format!("poll on xsks failed: {}", errno))?;
+            }
+
+            let mut pfds = pollfds.iter();
+            for q in self.queues.iter() {
+                for (xsk, pfd) in q.xsks.iter().zip(&mut pfds) {
+                    if pfd.revents & libc::POLLIN != 0 {
+                        q.recv(xsk, &mut f);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Fd(pub(crate) c_int);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// A memory mapping, unmapped on drop.
+#[derive(Debug)]
+pub(crate) struct Mmap {
+    pub(crate) ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mmap {
+    pub(crate) fn new(
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: libc::off_t,
+        what: &str,
+    ) -> Result<Self, StdError> {
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, prot, flags, fd, offset) };
+        if ptr == libc::MAP_FAILED {
+            let errno = nix::errno::Errno::last();
+            Err(format!("mmap {} failed: {}", what, errno))?;
+        }
+
+        Ok(Mmap { ptr, len })
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// A single-producer single-consumer ring shared with the kernel.
+#[derive(Debug)]
+struct Ring {
+    _mmap: Mmap,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    entries: *mut c_void,
+    size: u32,
+}
+
+impl Ring {
+    fn new(
+        fd: c_int,
+        off: &if_xdp::xdp_ring_offset,
+        size: u32,
+        entry_size: usize,
+        pgoff: u64,
+        what: &str,
+    ) -> Result<Self, StdError> {
+        let len = off.desc as usize + size as usize * entry_size;
+        let mmap = Mmap::new(
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd,
+            pgoff as _,
+            what,
+        )?;
+
+        let base = mmap.ptr as *mut u8;
+        Ok(unsafe {
+            Ring {
+                producer: base.add(off.producer as usize) as *const AtomicU32,
+                consumer: base.add(off.consumer as usize) as *const AtomicU32,
+                entries: base.add(off.desc as usize) as *mut c_void,
+                size,
+                _mmap: mmap,
+            }
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    /// `idx` is a free-running ring index.
+    unsafe fn entry<T>(&self, idx: u32) -> *mut T {
+        (self.entries as *mut T).add((idx & (self.size - 1)) as usize)
+    }
+}
+
+/// The AF_XDP sockets of every shard on one rx queue, sharing one UMEM.
+// Fields drop in order: close the sockets before unmapping their rings and UMEM.
+#[derive(Debug)]
+struct QueueXsks {
+    // xsks[0] registered the UMEM, and the fill and completion rings are its.
+    xsks: Vec<Xsk>,
+    fill: Ring,
+    _comp: Ring,
+    umem: Mmap,
+}
+
+/// One shard's AF_XDP socket on a queue.
+#[derive(Debug)]
+struct Xsk {
+    fd: Fd,
+    shard: usize,
+    rx: Ring,
+}
+
+impl QueueXsks {
+    fn new(ifindex: u32, queue: u32, num_shards: usize) -> Result<Self, StdError> {
+        let owner = xsk_socket()?;
+        let umem = Mmap::new(
+            NUM_FRAMES * FRAME_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+            "umem",
+        )?;
+
+        let reg = if_xdp::xdp_umem_reg {
+            addr: umem.ptr as u64,
+            len: umem.len as u64,
+            chunk_size: FRAME_SIZE as u32,
+            headroom: 0,
+            ..Default::default()
+        };
+        setsockopt(&owner, if_xdp::XDP_UMEM_REG, &reg, "XDP_UMEM_REG")?;
+        setsockopt(
+            &owner,
+            if_xdp::XDP_UMEM_FILL_RING,
+            &FILL_RING_SIZE,
+            "XDP_UMEM_FILL_RING",
+        )?;
+        setsockopt(
+            &owner,
+            if_xdp::XDP_UMEM_COMPLETION_RING,
+            &COMP_RING_SIZE,
+            "XDP_UMEM_COMPLETION_RING",
+        )?;
+
+        let off = mmap_offsets(&owner)?;
+        let fill = Ring::new(
+            owner.0,
+            &off.fr,
+            FILL_RING_SIZE,
+            std::mem::size_of::<u64>(),
+            if_xdp::XDP_UMEM_PGOFF_FILL_RING as _,
+            "fill ring",
+        )?;
+        let comp = Ring::new(
+            owner.0,
+            &off.cr,
+            COMP_RING_SIZE,
+            std::mem::size_of::<u64>(),
+            if_xdp::XDP_UMEM_PGOFF_COMPLETION_RING as _,
+            "completion ring",
+        )?;
+
+        // give the kernel every frame to receive into.
+        for i in 0..NUM_FRAMES as u32 {
+            unsafe { *fill.entry::<u64>(i) = i as u64 * FRAME_SIZE as u64 };
+        }
+        fill.producer().store(NUM_FRAMES as u32, Ordering::Release);
+
+        let owner = Xsk::new(owner, 0, ifindex, queue, None)?;
+        let owner_fd = owner.fd.0;
+        let mut xsks = Vec::with_capacity(num_shards);
+        xsks.push(owner);
+        for shard in 1..num_shards {
+            xsks.push(Xsk::new(
+                xsk_socket()?,
+                shard,
+                ifindex,
+                queue,
+                Some(owner_fd),
+            )?);
+        }
+
+        Ok(QueueXsks {
+            xsks,
+            fill,
+            _comp: comp,
+            umem,
+        })
+    }
+
+    /// Hand everything on `xsk`'s rx ring to `f`, then give the frames back on the fill ring.
+    ///
+    /// Every frame on an rx ring came off the fill ring, so there is always room for it there.
+    fn recv(&self, xsk: &Xsk, f: &mut impl FnMut(usize, &[u8])) {
+        let cons = xsk.rx.consumer().load(Ordering::Relaxed);
+        let prod = xsk.rx.producer().load(Ordering::Acquire);
+        let fill_prod = self.fill.producer().load(Ordering::Relaxed);
+        let n = prod.wrapping_sub(cons);
+
+        for i in 0..n {
+            let desc = unsafe { *xsk.rx.entry::<if_xdp::xdp_desc>(cons.wrapping_add(i)) };
+            let frame = unsafe {
+                std::slice::from_raw_parts(
+                    (self.umem.ptr as *const u8).add(desc.addr as usize),
+                    desc.len as usize,
+                )
+            };
+            f(xsk.shard, frame);
+
+            let frame_addr = desc.addr & !(FRAME_SIZE as u64 - 1);
+            unsafe { *self.fill.entry::<u64>(fill_prod.wrapping_add(i)) = frame_addr };
+        }
+
+        xsk.rx.consumer().store(prod, Ordering::Release);
+        self.fill
+            .producer()
+            .store(fill_prod.wrapping_add(n), Ordering::Release);
+    }
+}
+
+impl Xsk {
+    /// Set up `fd`'s rx ring and bind it. `shared_umem_fd` is the bound socket whose UMEM it
+    /// uses, or `None` if `fd` registered its own.
+    fn new(
+        fd: Fd,
+        shard: usize,
+        ifindex: u32,
+        queue: u32,
+        shared_umem_fd: Option<c_int>,
+    ) -> Result<Self, StdError> {
+        setsockopt(&fd, if_xdp::XDP_RX_RING, &RX_RING_SIZE, "XDP_RX_RING")?;
+        let off = mmap_offsets(&fd)?;
+        let rx = Ring::new(
+            fd.0,
+            &off.rx,
+            RX_RING_SIZE,
+            std::mem::size_of::<if_xdp::xdp_desc>(),
+            if_xdp::XDP_PGOFF_RX_RING as _,
+            "rx ring",
+        )?;
+
+        let mut sxdp = if_xdp::sockaddr_xdp {
+            sxdp_family: AF_XDP as _,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue,
+            ..Default::default()
+        };
+        if let Some(umem_fd) = shared_umem_fd {
+            sxdp.sxdp_flags = if_xdp::XDP_SHARED_UMEM as _;
+            sxdp.sxdp_shared_umem_fd = umem_fd as _;
+        }
+
+        let ok = unsafe {
+            libc::bind(
+                fd.0,
+                &sxdp as *const _ as *const libc::sockaddr,
+                std::mem::size_of_val(&sxdp) as _,
+            )
+        };
+        if ok < 0 {
+            let errno = nix::errno::Errno::last();
+            Err(format!(
+                "bind AF_XDP socket for shard {} to ifindex {} queue {} failed: {}",
+                shard, ifindex, queue, errno
+            ))?;
+        }
+
+        Ok(Xsk { fd, shard, rx })
+    }
+}
+
+fn xsk_socket() -> Result<Fd, StdError> {
+    let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) };
+    if fd < 0 {
+        let errno = nix::errno::Errno::last();
+        Err(format!("AF_XDP socket failed: {}", errno))?;
+    }
+
+    Ok(Fd(fd))
+}
+
+fn mmap_offsets(fd: &Fd) -> Result<if_xdp::xdp_mmap_offsets, StdError> {
+    let mut off = if_xdp::xdp_mmap_offsets::default();
+    let mut optlen = std::mem::size_of_val(&off) as libc::socklen_t;
+    let ok = unsafe {
+        libc::getsockopt(
+            fd.0,
+            SOL_XDP,
+            if_xdp::XDP_MMAP_OFFSETS as _,
+            &mut off as *mut _ as *mut _,
+            &mut optlen as *mut _,
+        )
+    };
+    if ok < 0 {
+        let errno = nix::errno::Errno::last();
+        Err(format!("getsockopt XDP_MMAP_OFFSETS failed: {}", errno))?;
+    }
+
+    Ok(off)
+}
+
+fn setsockopt<T>(fd: &Fd, opt: u32, val: &T, what: &str) -> Result<(), StdError> {
+    let ok = unsafe {
+        libc::setsockopt(
+            fd.0,
+            SOL_XDP,
+            opt as _,
+            val as *const T as *const _,
+            std::mem::size_of::<T>() as _,
+        )
+    };
+    if ok < 0 {
+        let errno = nix::errno::Errno::last();
+        Err(format!("setsockopt {} failed: {}", what, errno))?;
+    }
+
+    Ok(())
+}
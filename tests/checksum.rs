@@ -0,0 +1,818 @@
+//! Push packets through the XDP program with `BPF_PROG_TEST_RUN` and check that L4 checksums
+//! are still valid after the destination port is rewritten.
+//!
+//! These need CAP_SYS_ADMIN (they attach to `lo`), so they are ignored by default:
+//! `sudo -E cargo test --test checksum -- --ignored`
+
+const TC_ACT_OK: u32 = 0;
+const XDP_DROP: u32 = 1;
+const XDP_PASS: u32 = 2;
+const ORIG_PORT: u16 = 4242;
+const SHARD_PORTS: [u16; 3] = [4243, 4244, 4245];
+
+fn csum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+fn csum_add(mut sum: u32, buf: &[u8]) -> u32 {
+    for c in buf.chunks(2) {
+        let hi = (c[0] as u32) << 8;
+        let lo = if c.len() > 1 { c[1] as u32 } else { 0 };
+        sum += hi | lo;
+    }
+
+    sum
+}
+
+fn pseudo_hdr_sum(src: &[u8], dst: &[u8], proto: u8, l4_len: usize) -> u32 {
+    let sum = csum_add(0, src);
+    let sum = csum_add(sum, dst);
+    sum + proto as u32 + l4_len as u32
+}
+
+/// Valid iff the one's complement sum over pseudo-header and segment is all ones.
+fn l4_csum_ok(src: &[u8], dst: &[u8], proto: u8, l4: &[u8]) -> bool {
+    let sum = pseudo_hdr_sum(src, dst, proto, l4.len());
+    csum_fold(csum_add(sum, l4)) == 0xffff
+}
+
+fn fill_l4_csum(src: &[u8], dst: &[u8], proto: u8, l4: &mut [u8], csum_off: usize) {
+    let sum = pseudo_hdr_sum(src, dst, proto, l4.len());
+    let mut csum = !csum_fold(csum_add(sum, l4));
+    if proto == 17 && csum == 0 {
+        csum = 0xffff;
+    }
+
+    l4[csum_off..csum_off + 2].copy_from_slice(&csum.to_be_bytes());
+}
+
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH_ACK: u8 = 0x18;
+
+fn l4_segment(proto: u8, src_port: u16, key: u32, tcp_flags: u8) -> Vec<u8> {
+    let mut payload = key.to_le_bytes().to_vec();
+    payload.extend_from_slice(&[0xab; 12]);
+    l4_segment_payload(proto, src_port, &payload, tcp_flags)
+}
+
+fn l4_segment_payload(proto: u8, src_port: u16, payload: &[u8], tcp_flags: u8) -> Vec<u8> {
+    let mut l4 = match proto {
+        17 => {
+            let mut h = vec![0u8; 8];
+            h[4..6].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+            h
+        }
+        6 => {
+            let mut h = vec![0u8; 20];
+            h[4..8].copy_from_slice(&1u32.to_be_bytes()); // seq
+            h[12] = 5 << 4; // doff
+            h[13] = tcp_flags;
+            h[14..16].copy_from_slice(&65535u16.to_be_bytes());
+            h
+        }
+        _ => unreachable!(),
+    };
+
+    l4[0..2].copy_from_slice(&src_port.to_be_bytes());
+    l4[2..4].copy_from_slice(&ORIG_PORT.to_be_bytes());
+    l4.extend_from_slice(payload);
+    l4
+}
+
+fn l4_csum_off(proto: u8) -> usize {
+    match proto {
+        17 => 6,
+        6 => 16,
+        _ => unreachable!(),
+    }
+}
+
+const V4_SRC: [u8; 4] = [10, 0, 0, 1];
+const V4_DST: [u8; 4] = [10, 0, 0, 2];
+
+/// Returns (packet, offset of L4 header).
+fn ipv4_packet(proto: u8, key: u32, tcp_flags: u8, with_csum: bool) -> (Vec<u8>, usize) {
+    ipv4_packet_opts(proto, key, tcp_flags, with_csum, &[])
+}
+
+/// Like [`ipv4_packet`], with IP options `opts`, a multiple of 4 bytes long.
+fn ipv4_packet_opts(
+    proto: u8,
+    key: u32,
+    tcp_flags: u8,
+    with_csum: bool,
+    opts: &[u8],
+) -> (Vec<u8>, usize) {
+    let mut l4 = l4_segment(proto, 5000 + key as u16, key, tcp_flags);
+    if with_csum {
+        fill_l4_csum(&V4_SRC, &V4_DST, proto, &mut l4, l4_csum_off(proto));
+    }
+
+    ipv4_encap(proto, &l4, opts)
+}
+
+/// A UDP packet for ORIG_PORT over IPv4 carrying `payload`. Returns (packet, offset of L4 header).
+fn udp_packet(payload: &[u8]) -> (Vec<u8>, usize) {
+    let mut l4 = l4_segment_payload(17, 5000, payload, 0);
+    fill_l4_csum(&V4_SRC, &V4_DST, 17, &mut l4, l4_csum_off(17));
+    ipv4_encap(17, &l4, &[])
+}
+
+/// Wrap the L4 segment `l4` in Ethernet and IPv4 headers, with IP options `opts`.
+fn ipv4_encap(proto: u8, l4: &[u8], opts: &[u8]) -> (Vec<u8>, usize) {
+    let mut pkt = vec![0u8; 14];
+    pkt[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+
+    let mut ip = vec![0u8; 20];
+    ip.extend_from_slice(opts);
+    let tot_len = (ip.len() + l4.len()) as u16;
+    ip[0] = 0x40 | (ip.len() / 4) as u8;
+    ip[2..4].copy_from_slice(&tot_len.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = proto;
+    ip[12..16].copy_from_slice(&V4_SRC);
+    ip[16..20].copy_from_slice(&V4_DST);
+    set_ipv4_csum(&mut ip);
+
+    let l4_off = 14 + ip.len();
+    pkt.extend_from_slice(&ip);
+    pkt.extend_from_slice(l4);
+    (pkt, l4_off)
+}
+
+/// Recompute the checksum of the IPv4 header `ip`, as long as its IHL says.
+fn set_ipv4_csum(ip: &mut [u8]) {
+    let len = ((ip[0] & 0xf) as usize * 4).min(ip.len());
+    ip[10..12].copy_from_slice(&[0, 0]);
+    let ip_csum = !csum_fold(csum_add(0, &ip[..len]));
+    ip[10..12].copy_from_slice(&ip_csum.to_be_bytes());
+}
+
+const V6_SRC: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+const V6_DST: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+fn ipv6_packet(proto: u8, key: u32, tcp_flags: u8) -> (Vec<u8>, usize) {
+    ipv6_packet_ext(proto, key, tcp_flags, &[])
+}
+
+const IPV6_HOPOPTS: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AH: u8 = 51;
+const IPV6_DSTOPTS: u8 = 60;
+
+/// An IPv6 extension header of type `ext`, followed by `next`. Fragment headers are for the first
+/// fragment.
+fn ipv6_ext_hdr(ext: u8, next: u8) -> Vec<u8> {
+    match ext {
+        // a PadN option fills the 8 bytes.
+        IPV6_HOPOPTS | IPV6_DSTOPTS => vec![next, 0, 1, 4, 0, 0, 0, 0],
+        IPV6_ROUTING => vec![next, 0, 0, 0, 0, 0, 0, 0],
+        // offset 0, more fragments.
+        IPV6_FRAGMENT => vec![next, 0, 0, 1, 0, 0, 0, 42],
+        // length is in 4-byte units, minus 2.
+        IPV6_AH => vec![next, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1],
+        _ => unreachable!(),
+    }
+}
+
+/// Like [`ipv6_packet`], with the extension headers `exts` between the IPv6 and L4 headers.
+fn ipv6_packet_ext(proto: u8, key: u32, tcp_flags: u8, exts: &[u8]) -> (Vec<u8>, usize) {
+    let mut l4 = l4_segment(proto, 5000 + key as u16, key, tcp_flags);
+    fill_l4_csum(&V6_SRC, &V6_DST, proto, &mut l4, l4_csum_off(proto));
+
+    let mut ext_hdrs = vec![];
+    for (i, &ext) in exts.iter().enumerate() {
+        let next = exts.get(i + 1).copied().unwrap_or(proto);
+        ext_hdrs.extend(ipv6_ext_hdr(ext, next));
+    }
+
+    let mut pkt = vec![0u8; 14];
+    pkt[12..14].copy_from_slice(&0x86ddu16.to_be_bytes());
+
+    let mut ip = vec![0u8; 40];
+    ip[0] = 0x60;
+    ip[4..6].copy_from_slice(&((ext_hdrs.len() + l4.len()) as u16).to_be_bytes());
+    ip[6] = exts.first().copied().unwrap_or(proto);
+    ip[7] = 64;
+    ip[8..24].copy_from_slice(&V6_SRC);
+    ip[24..40].copy_from_slice(&V6_DST);
+
+    pkt.extend_from_slice(&ip);
+    pkt.extend_from_slice(&ext_hdrs);
+    pkt.extend_from_slice(&l4);
+    (pkt, 14 + 40 + ext_hdrs.len())
+}
+
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88a8;
+
+/// Insert VLAN tags with the given TPIDs, outermost first, after the MAC addresses of `pkt`.
+fn vlan_tagged(pkt: &[u8], l4_off: usize, tpids: &[u16]) -> (Vec<u8>, usize) {
+    let mut tagged = pkt[..12].to_vec();
+    for (i, tpid) in tpids.iter().enumerate() {
+        tagged.extend_from_slice(&tpid.to_be_bytes());
+        tagged.extend_from_slice(&(100 + i as u16).to_be_bytes()); // TCI: vlan id
+    }
+
+    tagged.extend_from_slice(&pkt[12..]);
+    (tagged, l4_off + 4 * tpids.len())
+}
+
+fn sharded_handle() -> xdp_shard::BpfHandles {
+    let mut prog = xdp_shard::BpfHandles::load_on_interface_name("lo").unwrap();
+    prog.shard_ports(ORIG_PORT, &SHARD_PORTS, 0, 4).unwrap();
+    prog
+}
+
+fn check_rewritten(
+    prog: &xdp_shard::BpfHandles,
+    pkt: &[u8],
+    l4_off: usize,
+    src: &[u8],
+    dst: &[u8],
+    proto: u8,
+) -> u16 {
+    let (act, out) = prog.test_run(pkt).unwrap();
+    assert_eq!(act, XDP_PASS);
+    assert_eq!(out.len(), pkt.len());
+
+    let l4 = &out[l4_off..];
+    let dport = u16::from_be_bytes([l4[2], l4[3]]);
+    assert!(SHARD_PORTS.contains(&dport), "port not rewritten: {}", dport);
+    assert!(
+        l4_csum_ok(src, dst, proto, l4),
+        "bad checksum after rewrite to {}",
+        dport
+    );
+    dport
+}
+
+// VLAN and QinQ tags, and IPv6 extension headers, are stepped over to find the L4 header.
+fn check_encapsulated(prog: &xdp_shard::BpfHandles, key: u32) {
+    for tpids in [
+        &[ETH_P_8021Q][..],
+        &[ETH_P_8021AD, ETH_P_8021Q][..],
+        &[ETH_P_8021Q, ETH_P_8021Q][..],
+    ]
+    .iter()
+    {
+        for &proto in [17, 6].iter() {
+            let (pkt, l4_off) = ipv4_packet(proto, key, TCP_SYN, true);
+            let (pkt, l4_off) = vlan_tagged(&pkt, l4_off, tpids);
+            check_rewritten(prog, &pkt, l4_off, &V4_SRC, &V4_DST, proto);
+
+            let (pkt, l4_off) = ipv6_packet(proto, key, TCP_SYN);
+            let (pkt, l4_off) = vlan_tagged(&pkt, l4_off, tpids);
+            check_rewritten(prog, &pkt, l4_off, &V6_SRC, &V6_DST, proto);
+        }
+    }
+
+    for exts in [
+        &[IPV6_HOPOPTS][..],
+        &[IPV6_HOPOPTS, IPV6_ROUTING, IPV6_DSTOPTS][..],
+        &[IPV6_FRAGMENT][..],
+        &[IPV6_HOPOPTS, IPV6_AH, IPV6_FRAGMENT, IPV6_DSTOPTS][..],
+    ]
+    .iter()
+    {
+        let (pkt, l4_off) = ipv6_packet_ext(17, key, 0, exts);
+        check_rewritten(prog, &pkt, l4_off, &V6_SRC, &V6_DST, 17);
+
+        // a different source port is a different TCP connection.
+        let (pkt, l4_off) = ipv6_packet_ext(6, key + 1000, TCP_SYN, exts);
+        check_rewritten(prog, &pkt, l4_off, &V6_SRC, &V6_DST, 6);
+
+        let (pkt, l4_off) = vlan_tagged(&pkt, l4_off, &[ETH_P_8021AD, ETH_P_8021Q]);
+        check_rewritten(prog, &pkt, l4_off, &V6_SRC, &V6_DST, 6);
+    }
+}
+
+fn total_ip_counts(prog: &mut xdp_shard::BpfHandles) -> xdp_shard::IpCounts {
+    let (curr, _) = prog.get_stats().unwrap();
+    let mut total = xdp_shard::IpCounts::default();
+    for cpus in curr.get_rxq_cpu_ip_counts() {
+        for c in cpus {
+            total.options += c.options;
+            total.bad_hdr += c.bad_hdr;
+            total.fragments += c.fragments;
+        }
+    }
+
+    total
+}
+
+fn total_drops(prog: &xdp_shard::BpfHandles, reason: xdp_shard::DropReason) -> usize {
+    let drops = prog.get_drop_stats().unwrap();
+    drops
+        .iter()
+        .map(|cpu| cpu.get(&reason).copied().unwrap_or(0))
+        .sum()
+}
+
+// IPv4 options move the L4 header, and a bad IHL gets the packet dropped.
+fn check_ipv4_hdr_len(prog: &mut xdp_shard::BpfHandles) {
+    let before = total_ip_counts(prog);
+    let opts: [&[u8]; 3] = [
+        &[1, 1, 1, 0],             // NOP NOP NOP EOL
+        &[7, 7, 4, 0, 0, 0, 0, 0], // record route, one slot
+        &[1; 40],                  // longest possible, IHL 15
+    ];
+    for opts in opts.iter() {
+        for &proto in [17, 6].iter() {
+            let (pkt, l4_off) = ipv4_packet_opts(proto, 7, TCP_SYN, true, opts);
+            check_rewritten(prog, &pkt, l4_off, &V4_SRC, &V4_DST, proto);
+        }
+    }
+
+    let after = total_ip_counts(prog);
+    assert_eq!(after.options - before.options, opts.len() * 2);
+    assert_eq!(after.bad_hdr, before.bad_hdr);
+
+    let drops_before = total_drops(prog, xdp_shard::DropReason::BadIpHeader);
+    let (good, _) = ipv4_packet(17, 7, 0, true);
+    let mut bad_pkts = vec![];
+    // IHL below the minimum of 5.
+    for ihl in 0..5 {
+        let mut pkt = good.clone();
+        pkt[14] = 0x40 | ihl;
+        set_ipv4_csum(&mut pkt[14..]);
+        bad_pkts.push(pkt);
+    }
+
+    // IHL past the end of the packet.
+    let mut pkt = good[..14 + 20 + 8].to_vec();
+    pkt[14] = 0x4f;
+    set_ipv4_csum(&mut pkt[14..]);
+    bad_pkts.push(pkt);
+
+    for pkt in bad_pkts.iter() {
+        let (act, out) = prog.test_run(pkt).unwrap();
+        assert_eq!(act, XDP_DROP);
+        assert_eq!(&out, pkt);
+    }
+
+    let drops_after = total_drops(prog, xdp_shard::DropReason::BadIpHeader);
+    assert_eq!(drops_after - drops_before, bad_pkts.len());
+    let after_bad = total_ip_counts(prog);
+    assert_eq!(after_bad.bad_hdr - after.bad_hdr, bad_pkts.len());
+}
+
+// Non-first fragments have no L4 header, so they pass untouched even though their payload looks
+// like one for ORIG_PORT. First fragments are sharded as usual.
+fn check_fragments(prog: &mut xdp_shard::BpfHandles) {
+    const MORE_FRAGMENTS: u16 = 0x2000;
+    const FRAG_OFFSET: u16 = 185; // in 8-byte units
+
+    let before = total_ip_counts(prog);
+    for &proto in [17, 6].iter() {
+        let (mut pkt, l4_off) = ipv4_packet(proto, 9, TCP_SYN, true);
+        pkt[14 + 6..14 + 8].copy_from_slice(&MORE_FRAGMENTS.to_be_bytes());
+        set_ipv4_csum(&mut pkt[14..]);
+        check_rewritten(prog, &pkt, l4_off, &V4_SRC, &V4_DST, proto);
+
+        for &frag_off in [FRAG_OFFSET | MORE_FRAGMENTS, FRAG_OFFSET].iter() {
+            let (mut pkt, _) = ipv4_packet(proto, 9, TCP_SYN, true);
+            pkt[14 + 6..14 + 8].copy_from_slice(&frag_off.to_be_bytes());
+            set_ipv4_csum(&mut pkt[14..]);
+            let (act, out) = prog.test_run(&pkt).unwrap();
+            assert_eq!(act, XDP_PASS);
+            assert_eq!(out, pkt);
+        }
+
+        // the fragment header's offset field is offset << 3 | more fragments.
+        for &frag_off in [FRAG_OFFSET << 3 | 1, FRAG_OFFSET << 3].iter() {
+            let (mut pkt, _) = ipv6_packet_ext(proto, 9, TCP_SYN, &[IPV6_FRAGMENT]);
+            pkt[14 + 40 + 2..14 + 40 + 4].copy_from_slice(&frag_off.to_be_bytes());
+            let (act, out) = prog.test_run(&pkt).unwrap();
+            assert_eq!(act, XDP_PASS);
+            assert_eq!(out, pkt);
+        }
+    }
+
+    let after = total_ip_counts(prog);
+    assert_eq!(after.fragments - before.fragments, 2 * 4);
+}
+
+fn short_msgs(prog: &xdp_shard::BpfHandles) -> usize {
+    let stats = prog.get_shard_stats(ORIG_PORT).unwrap();
+    stats.iter().map(|s| s.short_msg).sum()
+}
+
+// A payload too short for the shard key is passed unsharded, and counted.
+fn check_short_payload(prog: &xdp_shard::BpfHandles) {
+    let before = short_msgs(prog);
+    // the key is the first 4 bytes of the payload. keep 2.
+    let (pkt, l4_off) = ipv4_packet(17, 11, 0, false);
+    let mut pkt = pkt[..l4_off + 8 + 2].to_vec();
+    let ip_len = (pkt.len() - 14) as u16;
+    pkt[14 + 2..14 + 4].copy_from_slice(&ip_len.to_be_bytes());
+    set_ipv4_csum(&mut pkt[14..]);
+    pkt[l4_off + 4..l4_off + 6].copy_from_slice(&10u16.to_be_bytes());
+
+    let (act, out) = prog.test_run(&pkt).unwrap();
+    assert_eq!(act, XDP_PASS);
+    assert_eq!(out, pkt);
+    assert_eq!(short_msgs(prog) - before, 1);
+}
+
+// The port a UDP packet carrying `payload` is sent to, ORIG_PORT if it is not sharded.
+fn udp_dest_port(prog: &xdp_shard::BpfHandles, payload: &[u8]) -> u16 {
+    let (pkt, l4_off) = udp_packet(payload);
+    let (act, out) = prog.test_run(&pkt).unwrap();
+    assert_eq!(act, XDP_PASS);
+    assert_eq!(out.len(), pkt.len());
+
+    let l4 = &out[l4_off..];
+    let dport = u16::from_be_bytes([l4[2], l4[3]]);
+    assert!(
+        l4_csum_ok(&V4_SRC, &V4_DST, 17, l4),
+        "bad checksum after rewrite to {}",
+        dport
+    );
+    dport
+}
+
+// The shard port of `payload` under a modulo rule over SHARD_PORTS, as clients would predict it.
+fn hashed_port(payload: &[u8], fields: &[(u8, u8)], hash: xdp_shard::ShardHash) -> u16 {
+    let key = xdp_shard::hash::shard_key(payload, fields).unwrap();
+    SHARD_PORTS[(hash.hash(&key) % SHARD_PORTS.len() as u64) as usize]
+}
+
+// A payload whose bytes vary with `i`.
+fn test_payload(i: u8, len: usize) -> Vec<u8> {
+    (0..len as u8)
+        .map(|j| i.wrapping_mul(31).wrapping_add(j.wrapping_mul(7)))
+        .collect()
+}
+
+// Keys of every field size are read whole, at their offset, and hashed like hash::shard_key.
+fn check_field_sizes(prog: &mut xdp_shard::BpfHandles) {
+    const OFFSET: u8 = 3;
+    for &field_size in [1u8, 2, 4, 8, 16].iter() {
+        prog.shard_ports(ORIG_PORT, &SHARD_PORTS, OFFSET, field_size)
+            .unwrap();
+        let fields = [(OFFSET, field_size)];
+        let mut ports = std::collections::HashSet::new();
+        for i in 0..32 {
+            let payload = test_payload(i, 24);
+            let dport = udp_dest_port(prog, &payload);
+            assert_eq!(dport, hashed_port(&payload, &fields, Default::default()));
+            ports.insert(dport);
+
+            // one byte short of the key.
+            let short = &payload[..(OFFSET + field_size) as usize - 1];
+            assert_eq!(udp_dest_port(prog, short), ORIG_PORT);
+        }
+
+        // all on one shard would make the comparisons above prove little.
+        assert!(ports.len() > 1, "field_size {}: one shard", field_size);
+    }
+}
+
+// A composite key is its fields concatenated in the rule's order. If any field ends past the
+// payload, the packet is not sharded.
+fn check_composite_keys(prog: &mut xdp_shard::BpfHandles) {
+    let modulo = xdp_shard::ShardMode::Modulo;
+    for fields in [[(10u8, 4u8), (1, 2)], [(1, 2), (10, 4)], [(4, 8), (4, 1)]].iter() {
+        prog.shard_ports_composite(ORIG_PORT, &SHARD_PORTS, fields, modulo)
+            .unwrap();
+        for i in 0..32 {
+            let payload = test_payload(i, 24);
+            let dport = udp_dest_port(prog, &payload);
+            assert_eq!(dport, hashed_port(&payload, fields, Default::default()));
+        }
+    }
+
+    // the second field ends 4 bytes past the payload.
+    let fields = [(0, 4), (20, 8)];
+    prog.shard_ports_composite(ORIG_PORT, &SHARD_PORTS, &fields, modulo)
+        .unwrap();
+    let before = short_msgs(prog);
+    assert_eq!(udp_dest_port(prog, &test_payload(0, 24)), ORIG_PORT);
+    assert_eq!(short_msgs(prog) - before, 1);
+
+    let payload = test_payload(0, 28);
+    let dport = udp_dest_port(prog, &payload);
+    assert_eq!(dport, hashed_port(&payload, &fields, Default::default()));
+}
+
+// Every hash the XDP program offers places keys where ShardHash::hash predicts. The key is 11
+// bytes, so that the hashes that work in 4- or 8-byte blocks have a tail too.
+fn check_shard_hashes(prog: &mut xdp_shard::BpfHandles) {
+    use xdp_shard::ShardHash;
+
+    let fields = [(1, 8), (12, 3)];
+    prog.shard_ports_composite(
+        ORIG_PORT,
+        &SHARD_PORTS,
+        &fields,
+        xdp_shard::ShardMode::Modulo,
+    )
+    .unwrap();
+    for &hash in [
+        ShardHash::Fnv1a,
+        ShardHash::SipHash13([0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908]),
+        ShardHash::Crc32,
+        ShardHash::XxHash32(0x9e37_79b1),
+        ShardHash::Identity,
+        ShardHash::IdentityLe,
+    ]
+    .iter()
+    {
+        prog.set_shard_hash(ORIG_PORT, hash).unwrap();
+        assert_eq!(prog.shard_hash(ORIG_PORT), Some(hash));
+        for i in 0..32 {
+            let payload = test_payload(i, 24);
+            let dport = udp_dest_port(prog, &payload);
+            assert_eq!(dport, hashed_port(&payload, &fields, hash), "{:?}", hash);
+        }
+    }
+
+    prog.set_shard_hash(ORIG_PORT, ShardHash::default())
+        .unwrap();
+}
+
+// The hash an IPv4 TCP connection from ipv4_packet's `key` is sharded on: FNV-1a of the
+// source and destination addresses, each padded to 16 bytes, and ports.
+fn tcp_flow_hash(key: u32) -> u64 {
+    let mut flow = [0u8; 36];
+    flow[0..4].copy_from_slice(&V4_SRC);
+    flow[16..20].copy_from_slice(&V4_DST);
+    flow[32..34].copy_from_slice(&(5000 + key as u16).to_be_bytes());
+    flow[34..36].copy_from_slice(&ORIG_PORT.to_be_bytes());
+    xdp_shard::hash::fnv1a_64(&flow)
+}
+
+// Range rules place integer keys by their split points, in either byte order: a key equal to a
+// split point goes above it. TCP connections have no key, and are spread by the FNV-1a hash of
+// their 4-tuple modulo the number of ports.
+fn check_ranges(prog: &mut xdp_shard::BpfHandles) {
+    use xdp_shard::KeyOrder;
+
+    let splits = [1000, 0x1_0000_0000, 0x00ff_0000_0000_0000];
+    let fields = [(2, 8)];
+    for &order in [KeyOrder::BigEndian, KeyOrder::LittleEndian].iter() {
+        prog.shard_ports_range(ORIG_PORT, &SHARD_PORTS, &splits, &fields, order)
+            .unwrap();
+        let send = |prog: &xdp_shard::BpfHandles, key: u64| {
+            let mut payload = vec![0xab; 2];
+            match order {
+                KeyOrder::BigEndian => payload.extend_from_slice(&key.to_be_bytes()),
+                KeyOrder::LittleEndian => payload.extend_from_slice(&key.to_le_bytes()),
+            }
+            payload.extend_from_slice(&[0xab; 6]);
+            udp_dest_port(prog, &payload)
+        };
+
+        for (i, &split) in splits.iter().enumerate() {
+            assert_eq!(send(prog, split - 1), SHARD_PORTS[i], "{:?}", order);
+            assert_eq!(send(prog, split), SHARD_PORTS[i + 1], "{:?}", order);
+            assert_eq!(send(prog, split + 1), SHARD_PORTS[i + 1], "{:?}", order);
+        }
+
+        assert_eq!(send(prog, 0), SHARD_PORTS[0]);
+        assert_eq!(send(prog, u64::MAX), SHARD_PORTS[2]);
+    }
+
+    // fresh source ports: connections already in the flow table keep their shard.
+    let mut ports = std::collections::HashSet::new();
+    for key in 20000..20032 {
+        let (pkt, l4_off) = ipv4_packet(6, key, TCP_SYN, true);
+        let dport = check_rewritten(prog, &pkt, l4_off, &V4_SRC, &V4_DST, 6);
+        let idx = tcp_flow_hash(key) % SHARD_PORTS.len() as u64;
+        assert_eq!(dport, SHARD_PORTS[idx as usize]);
+        ports.insert(dport);
+    }
+
+    assert!(ports.len() > 1, "all connections on one shard");
+
+    // one shard, no split points: it gets every key.
+    let one = [SHARD_PORTS[0]];
+    prog.shard_ports_range(ORIG_PORT, &one, &[], &fields, KeyOrder::BigEndian)
+        .unwrap();
+    for &key in [0u64, 1000, 0x1_0000_0000, u64::MAX].iter() {
+        let mut payload = vec![0xab; 2];
+        payload.extend_from_slice(&key.to_be_bytes());
+        assert_eq!(udp_dest_port(prog, &payload), SHARD_PORTS[0]);
+    }
+}
+
+// The source port a UDP reply from `port` leaves with, after the TC egress program.
+fn egress_src_port(prog: &xdp_shard::BpfHandles, port: u16) -> u16 {
+    let mut l4 = l4_segment_payload(17, port, &[0xab; 16], 0);
+    fill_l4_csum(&V4_SRC, &V4_DST, 17, &mut l4, l4_csum_off(17));
+    let (pkt, l4_off) = ipv4_encap(17, &l4, &[]);
+    let (act, out) = prog.test_run_egress(&pkt).unwrap();
+    assert_eq!(act, TC_ACT_OK);
+    assert_eq!(out.len(), pkt.len());
+
+    let l4 = &out[l4_off..];
+    let sport = u16::from_be_bytes([l4[0], l4[1]]);
+    assert!(
+        l4_csum_ok(&V4_SRC, &V4_DST, 17, l4),
+        "bad checksum after rewrite from {}",
+        sport
+    );
+    sport
+}
+
+// A pinned key skips the hash and counts its hits, and unpinning it hashes it again. Replies
+// from a port are unsharded while a rule or a pinned key still uses it.
+fn check_pinned_keys(prog: &mut xdp_shard::BpfHandles) {
+    const PIN_PORT: u16 = 4250;
+
+    prog.shard_ports(ORIG_PORT, &SHARD_PORTS, 0, 4).unwrap();
+    prog.unshard_egress().unwrap();
+    let fields = [(0, 4)];
+    let payload = test_payload(5, 24);
+    let key = &payload[..4];
+    let hashed = hashed_port(&payload, &fields, Default::default());
+    assert_ne!(hashed, PIN_PORT);
+    assert_eq!(egress_src_port(prog, PIN_PORT), PIN_PORT);
+
+    prog.pin_key(ORIG_PORT, key, PIN_PORT).unwrap();
+    for _ in 0..3 {
+        assert_eq!(udp_dest_port(prog, &payload), PIN_PORT);
+    }
+
+    let pinned = prog.pinned_keys(ORIG_PORT).unwrap();
+    assert_eq!(pinned.len(), 1);
+    assert_eq!(pinned[0].key, key);
+    assert_eq!(pinned[0].port, PIN_PORT);
+    assert_eq!(pinned[0].hits, 3);
+    assert_eq!(egress_src_port(prog, PIN_PORT), ORIG_PORT);
+
+    // other keys are still hashed.
+    let other = test_payload(6, 24);
+    assert_eq!(
+        udp_dest_port(prog, &other),
+        hashed_port(&other, &fields, Default::default())
+    );
+    assert_eq!(prog.pinned_keys(ORIG_PORT).unwrap()[0].hits, 3);
+
+    prog.unpin_key(ORIG_PORT, key).unwrap();
+    assert_eq!(udp_dest_port(prog, &payload), hashed);
+    assert!(prog.pinned_keys(ORIG_PORT).unwrap().is_empty());
+    assert_eq!(egress_src_port(prog, PIN_PORT), PIN_PORT);
+
+    // a key pinned to one of the rule's own shard ports.
+    let shared = SHARD_PORTS[1];
+    prog.pin_key(ORIG_PORT, key, shared).unwrap();
+    assert_eq!(udp_dest_port(prog, &payload), shared);
+    prog.unpin_key(ORIG_PORT, key).unwrap();
+    assert_eq!(egress_src_port(prog, shared), ORIG_PORT);
+
+    prog.pin_key(ORIG_PORT, key, shared).unwrap();
+    prog.shard_ports(ORIG_PORT, &[SHARD_PORTS[0], SHARD_PORTS[2]], 0, 4)
+        .unwrap();
+    assert_eq!(egress_src_port(prog, shared), ORIG_PORT);
+    prog.unpin_key(ORIG_PORT, key).unwrap();
+    assert_eq!(egress_src_port(prog, shared), shared);
+    assert_eq!(egress_src_port(prog, SHARD_PORTS[0]), ORIG_PORT);
+
+    prog.shard_ports(ORIG_PORT, &SHARD_PORTS, 0, 4).unwrap();
+}
+
+// A key sent more than SKETCH_HOT_MIN times among others comes out as the hottest, with at
+// least its real count. Nothing is counted while tracking is off.
+fn check_hot_keys(prog: &mut xdp_shard::BpfHandles) {
+    const SENDS: usize = xdp_shard::bindings::xdp_shard::SKETCH_HOT_MIN as usize + 36;
+
+    prog.shard_ports(ORIG_PORT, &SHARD_PORTS, 0, 4).unwrap();
+    let hot = test_payload(200, 24);
+    assert!(!prog.tracks_hot_keys(ORIG_PORT));
+    assert!(prog.hot_keys(ORIG_PORT, 5).is_err());
+    for _ in 0..SENDS {
+        udp_dest_port(prog, &hot);
+    }
+
+    prog.track_hot_keys(ORIG_PORT, true).unwrap();
+    assert!(prog.tracks_hot_keys(ORIG_PORT));
+    assert!(prog.hot_keys(ORIG_PORT, 5).unwrap().is_empty());
+
+    for i in 0..SENDS {
+        udp_dest_port(prog, &hot);
+        udp_dest_port(prog, &test_payload((i % 50) as u8, 24));
+    }
+
+    let keys = prog.hot_keys(ORIG_PORT, 5).unwrap();
+    assert!(!keys.is_empty());
+    assert_eq!(keys[0].key, &hot[..4]);
+    assert!(keys[0].count >= SENDS, "{} < {}", keys[0].count, SENDS);
+
+    prog.track_hot_keys(ORIG_PORT, false).unwrap();
+    assert!(!prog.tracks_hot_keys(ORIG_PORT));
+    assert!(prog.hot_keys(ORIG_PORT, 5).is_err());
+}
+
+// Sampled decisions carry the packet's rule, key, hash and shard. Run once per kind of sample
+// buffer.
+fn check_samples(prog: &mut xdp_shard::BpfHandles) {
+    use xdp_shard::sample::SampleReader;
+
+    let mut reader = SampleReader::new(prog).unwrap();
+    reader.poll(|_| ());
+    prog.sample_decisions(1).unwrap();
+
+    let mut sent = vec![];
+    for i in 0..8 {
+        let payload = test_payload(i, 24);
+        let dport = udp_dest_port(prog, &payload);
+        sent.push((payload[..4].to_vec(), dport));
+    }
+
+    // perf buffers are read CPU by CPU, so the samples need not be in order.
+    let mut samples = vec![];
+    reader.poll(|s| samples.push(s));
+    assert_eq!(samples.len(), sent.len());
+    for s in samples.iter() {
+        let (key, dport) = sent.iter().find(|(key, _)| *key == s.key).unwrap();
+        let hash = xdp_shard::ShardHash::default().hash(key);
+        let idx = (hash % SHARD_PORTS.len() as u64) as usize;
+        assert_eq!(s.src_addr, std::net::IpAddr::from(V4_SRC));
+        assert_eq!(s.orig_port, ORIG_PORT);
+        assert_eq!(&s.key, key);
+        assert_eq!(s.hash, hash);
+        assert_eq!(s.shard, Some(idx));
+        assert_eq!(s.port, *dport);
+        assert_eq!(s.port, SHARD_PORTS[idx]);
+        assert!(!s.tcp);
+    }
+
+    // fresh source ports: connections already in the flow table are not sampled.
+    let mut samples = vec![];
+    for key in 30000..30004 {
+        let (pkt, l4_off) = ipv4_packet(6, key, TCP_SYN, true);
+        let dport = check_rewritten(prog, &pkt, l4_off, &V4_SRC, &V4_DST, 6);
+        reader.poll(|s| samples.push(s));
+        let s = samples.pop().unwrap();
+        assert!(s.tcp);
+        assert!(s.key.is_empty());
+        assert_eq!(s.hash, tcp_flow_hash(key));
+        assert_eq!(s.port, dport);
+    }
+
+    prog.sample_decisions(0).unwrap();
+    udp_dest_port(prog, &test_payload(0, 24));
+    assert_eq!(reader.poll(|_| ()), 0);
+}
+
+// Only one XDP program can be attached to `lo` at a time, so everything runs in one test.
+#[test]
+#[ignore]
+fn rewritten_checksums_valid() {
+    let mut prog = sharded_handle();
+    for key in 0..64 {
+        let (pkt, l4_off) = ipv4_packet(17, key, 0, true);
+        check_rewritten(&prog, &pkt, l4_off, &V4_SRC, &V4_DST, 17);
+
+        let (pkt, l4_off) = ipv6_packet(17, key, 0);
+        check_rewritten(&prog, &pkt, l4_off, &V6_SRC, &V6_DST, 17);
+
+        // TCP picks the shard on the SYN, and later segments follow it.
+        let (pkt, l4_off) = ipv4_packet(6, key, TCP_SYN, true);
+        let syn_port = check_rewritten(&prog, &pkt, l4_off, &V4_SRC, &V4_DST, 6);
+        let (pkt, l4_off) = ipv4_packet(6, key, TCP_PSH_ACK, true);
+        let data_port = check_rewritten(&prog, &pkt, l4_off, &V4_SRC, &V4_DST, 6);
+        assert_eq!(syn_port, data_port);
+
+        let (pkt, l4_off) = ipv6_packet(6, key, TCP_SYN);
+        let syn_port = check_rewritten(&prog, &pkt, l4_off, &V6_SRC, &V6_DST, 6);
+        let (pkt, l4_off) = ipv6_packet(6, key, TCP_PSH_ACK);
+        let data_port = check_rewritten(&prog, &pkt, l4_off, &V6_SRC, &V6_DST, 6);
+        assert_eq!(syn_port, data_port);
+
+        // zero-checksum UDP must stay zero.
+        let (pkt, l4_off) = ipv4_packet(17, key, 0, false);
+        let (act, out) = prog.test_run(&pkt).unwrap();
+        assert_eq!(act, XDP_PASS);
+        let l4 = &out[l4_off..];
+        assert!(SHARD_PORTS.contains(&u16::from_be_bytes([l4[2], l4[3]])));
+        assert_eq!(&l4[6..8], &[0, 0]);
+
+        check_encapsulated(&prog, key);
+    }
+
+    check_ipv4_hdr_len(&mut prog);
+    check_fragments(&mut prog);
+    check_short_payload(&prog);
+    check_field_sizes(&mut prog);
+    check_composite_keys(&mut prog);
+    check_shard_hashes(&mut prog);
+    check_ranges(&mut prog);
+    check_pinned_keys(&mut prog);
+    check_hot_keys(&mut prog);
+    check_samples(&mut prog);
+
+    // again with perf buffers, which kernels before 5.8 use instead of a ring buffer.
+    drop(prog);
+    std::env::set_var("XDP_SHARD_PERF_SAMPLES", "1");
+    let mut prog = sharded_handle();
+    std::env::remove_var("XDP_SHARD_PERF_SAMPLES");
+    check_samples(&mut prog);
+}
@@ -0,0 +1,47 @@
+use xdp_shard::hash;
+
+#[test]
+fn reference_vectors() {
+    assert_eq!(hash::fnv1a_64(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(hash::fnv1a_64(b"a"), 0xaf63_dc4c_8601_ec8c);
+    // SipHash reference key 00..0f, then std's DefaultHasher (SipHash-1-3, zero key).
+    let (k0, k1) = (0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+    assert_eq!(hash::siphash13([k0, k1], b""), 0xabac_0158_050f_c4dc);
+    assert_eq!(
+        hash::siphash13([0, 0], b"Nobody inspects the spammish repetition"),
+        0x248f_2f37_815d_c387
+    );
+    assert_eq!(hash::crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(hash::xxhash32(0, b""), 0x02cc_5d05);
+    assert_eq!(hash::xxhash32(0, b"abc"), 0x32d1_53ff);
+    assert_eq!(
+        hash::xxhash32(0, b"Nobody inspects the spammish repetition"),
+        0xe229_3b2f
+    );
+    assert_eq!(hash::identity(&[0x12, 0x34]), 0x1234);
+    assert_eq!(
+        hash::identity(&[0xff, 1, 2, 3, 4, 5, 6, 7, 8]),
+        0x0102_0304_0506_0708
+    );
+    assert_eq!(hash::identity_le(&[0x12, 0x34]), 0x3412);
+}
+
+#[test]
+fn range_index_counts_split_points_below() {
+    let splits = [1_000_000, 2_000_000];
+    assert_eq!(hash::range_index(&splits, 0), 0);
+    assert_eq!(hash::range_index(&splits, 999_999), 0);
+    assert_eq!(hash::range_index(&splits, 1_000_000), 1);
+    assert_eq!(hash::range_index(&splits, 5_000_000), 2);
+    assert_eq!(hash::range_index(&[], 5), 0);
+}
+
+#[test]
+fn shard_key_concatenates_fields() {
+    let payload = b"0123456789";
+    assert_eq!(
+        hash::shard_key(payload, &[(4, 2), (0, 1)]),
+        Some(b"450".to_vec())
+    );
+    assert_eq!(hash::shard_key(payload, &[(8, 4)]), None);
+}
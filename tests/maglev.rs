@@ -0,0 +1,107 @@
+use xdp_shard::maglev;
+
+// MAGLEV_TABLE_SIZE
+const SIZE: usize = 65537;
+
+fn ports(n: u16) -> Vec<(u16, u32)> {
+    (0..n).map(|i| (4243 + i, 1)).collect()
+}
+
+// slot -> port, so tables built from different port lists compare.
+fn slot_ports(ports: &[(u16, u32)], table: &[u16]) -> Vec<u16> {
+    table.iter().map(|&i| ports[i as usize].0).collect()
+}
+
+fn moved(before: &[u16], after: &[u16]) -> usize {
+    before
+        .iter()
+        .zip(after.iter())
+        .filter(|(b, a)| b != a)
+        .count()
+}
+
+fn shares(num_ports: usize, table: &[u16]) -> Vec<usize> {
+    let mut counts = vec![0; num_ports];
+    for &i in table {
+        counts[i as usize] += 1;
+    }
+
+    counts
+}
+
+#[test]
+fn fills_every_slot_evenly() {
+    let ports = ports(7);
+    let table = maglev::populate(&ports, SIZE);
+    assert_eq!(table.len(), SIZE);
+    assert!(table.iter().all(|&i| (i as usize) < ports.len()));
+
+    // equal weights take turns, so shares differ by at most one slot.
+    let counts = shares(ports.len(), &table);
+    let min = *counts.iter().min().unwrap();
+    let max = *counts.iter().max().unwrap();
+    assert!(max - min <= 1, "uneven shares: {:?}", counts);
+}
+
+#[test]
+fn shares_follow_weights() {
+    let ports = vec![(4243, 1), (4244, 2), (4245, 5), (4246, 0)];
+    let table = maglev::populate(&ports, SIZE);
+    let counts = shares(ports.len(), &table);
+    assert_eq!(counts[3], 0);
+
+    let total_weight: u32 = ports.iter().map(|&(_, w)| w).sum();
+    for (&(port, weight), &count) in ports.iter().zip(counts.iter()) {
+        let expected = SIZE as f64 * weight as f64 / total_weight as f64;
+        assert!(
+            (count as f64 - expected).abs() <= SIZE as f64 * 0.01,
+            "port {} (weight {}) has {} slots, expected about {}",
+            port,
+            weight,
+            count,
+            expected
+        );
+    }
+}
+
+#[test]
+fn removing_a_port_moves_about_one_nth() {
+    let before_ports = ports(10);
+    let before = slot_ports(&before_ports, &maglev::populate(&before_ports, SIZE));
+
+    let removed = before_ports[3].0;
+    let after_ports: Vec<_> = before_ports
+        .iter()
+        .copied()
+        .filter(|&(p, _)| p != removed)
+        .collect();
+    let after = slot_ports(&after_ports, &maglev::populate(&after_ports, SIZE));
+
+    assert!(after.iter().all(|&p| p != removed));
+    let moved = moved(&before, &after);
+    let orphaned = before.iter().filter(|&&p| p == removed).count();
+    // the removed port's slots have to move. few others should.
+    assert!(moved >= orphaned);
+    assert!(
+        moved as f64 <= SIZE as f64 / before_ports.len() as f64 * 1.5,
+        "{} of {} slots moved",
+        moved,
+        SIZE
+    );
+}
+
+#[test]
+fn adding_a_port_moves_about_one_nth() {
+    let before_ports = ports(10);
+    let before = slot_ports(&before_ports, &maglev::populate(&before_ports, SIZE));
+    let after_ports = ports(11);
+    let after = slot_ports(&after_ports, &maglev::populate(&after_ports, SIZE));
+
+    let moved = moved(&before, &after);
+    assert!(
+        moved as f64 <= SIZE as f64 / after_ports.len() as f64 * 1.5,
+        "{} of {} slots moved",
+        moved,
+        SIZE
+    );
+}